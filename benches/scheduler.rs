@@ -0,0 +1,176 @@
+//! Compares the work-stealing scheduler with the single `Mutex<Receiver>`
+//! queue the pool used before it.
+//!
+//! Run with `cargo bench --bench scheduler`.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use threadpool::ThreadPool;
+
+const THREADS: usize = 4;
+const JOBS: usize = 200_000;
+const NESTED_OUTER: usize = 1_000;
+const NESTED_INNER: usize = 200;
+const LATENCY_SAMPLES: usize = 5_000;
+
+/// The original design: every worker locks one shared receiver.
+struct ChannelPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Box<dyn FnOnce() + Send>>>,
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        ChannelPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+/// The operations the benchmarks need from either pool.
+trait Pool: Send + Sync + 'static {
+    fn create(size: usize) -> Self;
+    fn run(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+impl Pool for ChannelPool {
+    fn create(size: usize) -> ChannelPool {
+        ChannelPool::new(size)
+    }
+
+    fn run(&self, job: Box<dyn FnOnce() + Send>) {
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Pool for ThreadPool {
+    fn create(size: usize) -> ThreadPool {
+        ThreadPool::new(size)
+    }
+
+    fn run(&self, job: Box<dyn FnOnce() + Send>) {
+        self.execute(job);
+    }
+}
+
+/// Waits until `counter` reaches `target`.
+fn wait_for(counter: &AtomicUsize, target: usize) {
+    while counter.load(Ordering::Acquire) < target {
+        thread::yield_now();
+    }
+}
+
+/// Many tiny jobs submitted from outside the pool.
+fn throughput<P: Pool>() -> Duration {
+    let pool = P::create(THREADS);
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..JOBS {
+        let done = Arc::clone(&done);
+        pool.run(Box::new(move || {
+            done.fetch_add(1, Ordering::Release);
+        }));
+    }
+    wait_for(&done, JOBS);
+    start.elapsed()
+}
+
+/// Jobs that each submit a batch of tiny jobs back into the pool.
+fn nested<P: Pool>() -> Duration {
+    let pool = Arc::new(P::create(THREADS));
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..NESTED_OUTER {
+        let inner_pool = Arc::clone(&pool);
+        let done = Arc::clone(&done);
+        pool.run(Box::new(move || {
+            for _ in 0..NESTED_INNER {
+                let done = Arc::clone(&done);
+                inner_pool.run(Box::new(move || {
+                    done.fetch_add(1, Ordering::Release);
+                }));
+            }
+        }));
+    }
+    wait_for(&done, NESTED_OUTER * NESTED_INNER);
+    let elapsed = start.elapsed();
+    // Make sure the pool is dropped here rather than on one of its workers.
+    while Arc::strong_count(&pool) > 1 {
+        thread::yield_now();
+    }
+    elapsed
+}
+
+/// Time from submitting a job to it starting on an idle pool.
+fn latency<P: Pool>() -> Vec<Duration> {
+    let pool = P::create(THREADS);
+    let (sender, receiver) = mpsc::channel();
+    let mut samples = Vec::with_capacity(LATENCY_SAMPLES);
+    for _ in 0..LATENCY_SAMPLES {
+        let sender = sender.clone();
+        let submitted = Instant::now();
+        pool.run(Box::new(move || sender.send(submitted.elapsed()).unwrap()));
+        samples.push(receiver.recv().unwrap());
+    }
+    samples.sort();
+    samples
+}
+
+fn percentile(samples: &[Duration], q: f64) -> Duration {
+    samples[((samples.len() - 1) as f64 * q).round() as usize]
+}
+
+fn report<P: Pool>(name: &str) {
+    let jobs_per_sec = |elapsed: Duration, jobs: usize| jobs as f64 / elapsed.as_secs_f64();
+    let flat = throughput::<P>();
+    let nested = nested::<P>();
+    let latency = latency::<P>();
+    println!(
+        "{:<14} {:>14.0} {:>14.0} {:>12?} {:>12?}",
+        name,
+        jobs_per_sec(flat, JOBS),
+        jobs_per_sec(nested, NESTED_OUTER * NESTED_INNER),
+        percentile(&latency, 0.5),
+        percentile(&latency, 0.99),
+    );
+}
+
+fn main() {
+    println!(
+        "{:<14} {:>14} {:>14} {:>12} {:>12}",
+        "scheduler", "flat jobs/s", "nested jobs/s", "p50 latency", "p99 latency"
+    );
+    report::<ChannelPool>("mutex-channel");
+    report::<ThreadPool>("work-stealing");
+}
@@ -0,0 +1,225 @@
+use std::{any::Any, error::Error, fmt, io, time::Duration};
+
+use crate::{watchdog::StuckJobHandler, PanicHandler, RejectionPolicy, StuckJob, ThreadPool};
+
+/// Configures and builds a [`ThreadPool`].
+///
+/// ```
+/// use threadpool::ThreadPoolBuilder;
+///
+/// let pool = ThreadPoolBuilder::new()
+///     .num_threads(4)
+///     .thread_name("db-pool")
+///     .stack_size(512 * 1024)
+///     .build()
+///     .unwrap();
+/// # drop(pool);
+/// ```
+#[derive(Default)]
+pub struct ThreadPoolBuilder {
+    pub(crate) num_threads: Option<usize>,
+    pub(crate) thread_name: Option<String>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) panic_handler: Option<PanicHandler>,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) rejection_policy: RejectionPolicy,
+    pub(crate) priority_aging: Option<Duration>,
+    pub(crate) job_timeout: Option<Duration>,
+    pub(crate) stuck_job_handler: Option<StuckJobHandler>,
+    pub(crate) replace_stuck_workers: bool,
+    pub(crate) max_threads: Option<usize>,
+    pub(crate) keep_alive: Option<Duration>,
+}
+
+/// The error returned by [`ThreadPoolBuilder::build`].
+#[derive(Debug)]
+pub enum BuildError {
+    /// The pool was configured with zero worker threads.
+    ZeroThreads,
+    /// The pool was configured with a queue capacity of zero.
+    ZeroQueueCapacity,
+    /// The pool was configured with a maximum number of threads below its
+    /// number of core threads.
+    MaxThreadsBelowCore,
+    /// A thread could not be spawned.
+    Spawn(io::Error),
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    /// Sets the number of worker threads. Defaults to
+    /// [`std::thread::available_parallelism`], or 1 if that is unknown.
+    ///
+    /// With [`max_threads`](ThreadPoolBuilder::max_threads), these are the
+    /// core threads, which stay alive however idle the pool is.
+    pub fn num_threads(mut self, num_threads: usize) -> ThreadPoolBuilder {
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Names the pool's threads `{prefix}-{id}`, e.g. `db-pool-3`. Threads
+    /// are unnamed by default.
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        self.thread_name = Some(prefix.into());
+        self
+    }
+
+    /// Sets the stack size, in bytes, of the pool's threads. Defaults to the
+    /// standard library's default for spawned threads.
+    pub fn stack_size(mut self, stack_size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Sets the handler called with the payload of panicking jobs. See
+    /// [`ThreadPool::set_panic_handler`].
+    pub fn panic_handler<F>(mut self, handler: F) -> ThreadPoolBuilder
+        where
+            F: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Limits the number of jobs waiting for a worker. The queue is unbounded
+    /// by default.
+    ///
+    /// Jobs submitted without a priority by a job running on one of the
+    /// pool's workers are not counted: they are queued on that worker's own
+    /// deque, which is unbounded, so they are never rejected. Nor are jobs of
+    /// a [scope](crate::ThreadPool::scope), or delayed and recurring jobs
+    /// once they are due, which are queued whatever the capacity.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Sets what happens to jobs submitted while the queue is full. Defaults
+    /// to [`RejectionPolicy::Block`].
+    pub fn rejection_policy(mut self, policy: RejectionPolicy) -> ThreadPoolBuilder {
+        self.rejection_policy = policy;
+        self
+    }
+
+    /// Raises the effective priority of queued jobs by one level for every
+    /// `interval` they wait, so low-priority jobs eventually run even while
+    /// higher-priority jobs keep arriving. Jobs do not age by default.
+    pub fn priority_aging(mut self, interval: Duration) -> ThreadPoolBuilder {
+        self.priority_aging = Some(interval);
+        self
+    }
+
+    /// Lets the pool grow beyond [`num_threads`](ThreadPoolBuilder::num_threads)
+    /// up to `max_threads` workers. A worker is added whenever a job is
+    /// submitted while every worker is busy; extra workers exit once they
+    /// have been idle for the [keep-alive](ThreadPoolBuilder::keep_alive). The
+    /// pool has a fixed size by default.
+    pub fn max_threads(mut self, max_threads: usize) -> ThreadPoolBuilder {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// Sets how long a worker beyond the core threads may wait for a job
+    /// before exiting. Defaults to 60 seconds. Has no effect without
+    /// [`max_threads`](ThreadPoolBuilder::max_threads).
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Sets the timeout of jobs submitted without one. A watchdog thread
+    /// reports jobs that run for longer; it is not allowed to stop them. Jobs
+    /// have no timeout by default, but see
+    /// [`ThreadPool::execute_with_timeout`].
+    pub fn job_timeout(mut self, timeout: Duration) -> ThreadPoolBuilder {
+        self.job_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the handler the watchdog calls, on its own thread, for every job
+    /// that outlives its timeout.
+    pub fn stuck_job_handler<F>(mut self, handler: F) -> ThreadPoolBuilder
+        where
+            F: Fn(&StuckJob) + Send + Sync + 'static,
+    {
+        self.stuck_job_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Makes the watchdog spawn a replacement worker for every worker stuck
+    /// on a job, so the pool keeps running jobs at its configured size. The
+    /// stuck worker exits once its job finishes. Off by default.
+    pub fn replace_stuck_workers(mut self, replace: bool) -> ThreadPoolBuilder {
+        self.replace_stuck_workers = replace;
+        self
+    }
+
+    /// Spawns the pool's threads.
+    pub fn build(self) -> Result<ThreadPool, BuildError> {
+        ThreadPool::from_builder(self)
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> BuildError {
+        BuildError::Spawn(e)
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ZeroThreads => write!(f, "threadpool must have at least one worker"),
+            BuildError::ZeroQueueCapacity => {
+                write!(f, "threadpool queue must hold at least one job")
+            }
+            BuildError::MaxThreadsBelowCore => {
+                write!(f, "threadpool maximum size must be at least its core size")
+            }
+            BuildError::Spawn(e) => write!(f, "failed to spawn thread: {}", e),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::ZeroThreads
+            | BuildError::ZeroQueueCapacity
+            | BuildError::MaxThreadsBelowCore => None,
+            BuildError::Spawn(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn names_threads_with_prefix() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name("db-pool")
+            .stack_size(256 * 1024)
+            .build()
+            .unwrap();
+
+        let name = pool
+            .submit(|| thread::current().name().map(str::to_owned))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert!(name == "db-pool-0" || name == "db-pool-1", "{}", name);
+    }
+
+    #[test]
+    fn zero_threads_is_an_error() {
+        let result = ThreadPoolBuilder::new().num_threads(0).build();
+        assert!(matches!(result, Err(BuildError::ZeroThreads)));
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, Weak,
+};
+
+/// A flag that asks jobs to stop.
+///
+/// Cancellation is cooperative: a job submitted with
+/// [`ThreadPool::submit_cancellable`](crate::ThreadPool::submit_cancellable)
+/// is skipped if its token is cancelled before a worker starts it, but once
+/// running it has to check [`is_cancelled`](CancellationToken::is_cancelled)
+/// itself.
+///
+/// Clones share the same flag, so one token passed to several jobs cancels
+/// them as a group. Tokens made with
+/// [`child_token`](CancellationToken::child_token) are also cancelled with
+/// their parent, but can be cancelled on their own.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    children: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Returns a new token that is cancelled when this one is.
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        let mut children = self.inner.children.lock().unwrap();
+        if self.is_cancelled() {
+            child.inner.cancelled.store(true, Ordering::SeqCst);
+        } else {
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&child.inner));
+        }
+        child
+    }
+
+    /// Cancels this token and all of its children.
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Inner {
+    fn cancel(&self) {
+        // Set under the lock so `child_token` cannot add a child after the
+        // children have been cancelled.
+        let children = {
+            let mut children = self.children.lock().unwrap();
+            if self.cancelled.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            std::mem::take(&mut *children)
+        };
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CancellationToken;
+    use crate::{tests::occupy_worker, JoinError, ThreadPool};
+    use std::{sync::mpsc, time::Duration};
+
+    #[test]
+    fn children_are_cancelled_with_their_parent() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+        let sibling = parent.child_token();
+
+        sibling.cancel();
+        assert!(!parent.is_cancelled());
+        assert!(!child.is_cancelled());
+
+        parent.cancel();
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[test]
+    fn cancelled_jobs_do_not_start() {
+        let pool = ThreadPool::new(1);
+        let release = occupy_worker(&pool);
+
+        let group = CancellationToken::new();
+        let handles: Vec<_> = (0..3)
+            .map(|i| pool.submit_cancellable(&group, move |_| i))
+            .collect();
+        group.cancel();
+        drop(release);
+
+        for handle in handles {
+            assert!(handle.is_cancelled());
+            assert!(matches!(handle.join(), Err(JoinError::Cancelled)));
+        }
+    }
+
+    #[test]
+    fn running_jobs_can_observe_cancellation() {
+        let pool = ThreadPool::new(1);
+        let token = CancellationToken::new();
+        let (started, wait_started) = mpsc::channel();
+        let handle = pool.submit_cancellable(&token, move |token| {
+            started.send(()).unwrap();
+            let mut polls = 0;
+            while !token.is_cancelled() {
+                polls += 1;
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            polls
+        });
+
+        wait_started.recv().unwrap();
+        token.cancel();
+        assert!(handle.join().is_ok());
+    }
+}
@@ -0,0 +1,76 @@
+use std::{error::Error, fmt};
+
+/// The error returned by [`ThreadPool::try_execute`](crate::ThreadPool::try_execute).
+///
+/// Every variant hands the rejected job back so it can be retried or run
+/// elsewhere.
+pub enum PoolError<F> {
+    /// The pool is shutting down and no longer accepts jobs.
+    ShutDown(F),
+    /// The job queue is full.
+    QueueFull(F),
+    /// There are no live workers to run the job.
+    NoWorkers(F),
+}
+
+impl<F> PoolError<F> {
+    /// Returns the rejected job.
+    pub fn into_inner(self) -> F {
+        match self {
+            PoolError::ShutDown(f) | PoolError::QueueFull(f) | PoolError::NoWorkers(f) => f,
+        }
+    }
+}
+
+impl<F> fmt::Debug for PoolError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::ShutDown(_) => "ShutDown(..)".fmt(f),
+            PoolError::QueueFull(_) => "QueueFull(..)".fmt(f),
+            PoolError::NoWorkers(_) => "NoWorkers(..)".fmt(f),
+        }
+    }
+}
+
+impl<F> fmt::Display for PoolError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::ShutDown(_) => write!(f, "threadpool is shutting down"),
+            PoolError::QueueFull(_) => write!(f, "threadpool queue is full"),
+            PoolError::NoWorkers(_) => write!(f, "threadpool has no live workers"),
+        }
+    }
+}
+
+impl<F> Error for PoolError<F> {}
+
+/// The error returned by
+/// [`ThreadPool::shutdown_timeout`](crate::ThreadPool::shutdown_timeout) when
+/// some workers were still running jobs at the deadline.
+#[derive(Debug)]
+pub struct ShutdownTimedOut {
+    workers: Vec<usize>,
+}
+
+impl ShutdownTimedOut {
+    pub(crate) fn new(workers: Vec<usize>) -> ShutdownTimedOut {
+        ShutdownTimedOut { workers }
+    }
+
+    /// Returns the ids of the workers that had not finished.
+    pub fn workers(&self) -> &[usize] {
+        &self.workers
+    }
+}
+
+impl fmt::Display for ShutdownTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} workers did not finish before the shutdown timeout",
+            self.workers.len()
+        )
+    }
+}
+
+impl Error for ShutdownTimedOut {}
@@ -0,0 +1,241 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::{help, stats, JoinError, ThreadPool};
+
+/// A batch of jobs that can be waited for together, created by
+/// [`ThreadPool::task_group`].
+///
+/// Waiting for a group only waits for its own jobs, so several groups can
+/// share a pool without holding each other up.
+pub struct TaskGroup<'pool, T> {
+    pool: &'pool ThreadPool,
+    state: Arc<GroupState<T>>,
+}
+
+/// The outcome of every job in a [`TaskGroup`], as returned by
+/// [`TaskGroup::wait`].
+#[derive(Debug)]
+pub struct GroupResult<T> {
+    results: Vec<Result<T, JoinError>>,
+}
+
+struct GroupState<T> {
+    results: Mutex<Results<T>>,
+    done: Condvar,
+}
+
+struct Results<T> {
+    /// One slot per job, in submission order, filled in once the job has
+    /// run or been dropped.
+    slots: Vec<Option<Result<T, JoinError>>>,
+    pending: usize,
+}
+
+/// A job's place in its group. Stores the job's result when dropped, or
+/// [`JoinError::Dropped`] if the job never ran.
+struct GroupJob<T> {
+    state: Arc<GroupState<T>>,
+    index: usize,
+    result: Option<Result<T, JoinError>>,
+}
+
+impl<'pool, T> TaskGroup<'pool, T>
+    where
+        T: Send + 'static,
+{
+    pub(crate) fn new(pool: &'pool ThreadPool) -> TaskGroup<'pool, T> {
+        TaskGroup {
+            pool,
+            state: Arc::new(GroupState {
+                results: Mutex::new(Results {
+                    slots: Vec::new(),
+                    pending: 0,
+                }),
+                done: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Queues `f` to run on one of the pool's workers as part of the group.
+    ///
+    /// If `f` panics, the panic is reported in the group's result; the
+    /// pool's panic handler is not called.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as [`ThreadPool::execute`].
+    pub fn execute<F>(&self, f: F)
+        where
+            F: FnOnce() -> T + Send + 'static,
+    {
+        let index = {
+            let mut results = self.state.results.lock().unwrap();
+            results.slots.push(None);
+            results.pending += 1;
+            results.slots.len() - 1
+        };
+        let job = GroupJob {
+            state: Arc::clone(&self.state),
+            index,
+            result: None,
+        };
+        self.pool.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+                stats::record_caught_panic();
+                JoinError::Panicked(payload)
+            });
+            job.finish(result);
+        });
+    }
+
+    /// Returns the number of jobs submitted to the group.
+    pub fn len(&self) -> usize {
+        self.state.results.lock().unwrap().slots.len()
+    }
+
+    /// Returns whether no jobs have been submitted to the group.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Blocks until every job in the group has finished and returns their
+    /// results.
+    ///
+    /// Calling `wait` from a job runs queued jobs on that job's worker while
+    /// it waits, so groups can be waited for from any number of jobs.
+    pub fn wait(self) -> GroupResult<T> {
+        // Group jobs borrow nothing from this stack, so a panicking panic
+        // handler can unwind straight away.
+        while self.state.results.lock().unwrap().pending > 0 {
+            match help(&self.pool.shared) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+        let mut results = self.state.results.lock().unwrap();
+        while results.pending > 0 {
+            results = self.state.done.wait(results).unwrap();
+        }
+        let results = results
+            .slots
+            .drain(..)
+            .map(|slot| slot.unwrap_or(Err(JoinError::Dropped)))
+            .collect();
+        GroupResult { results }
+    }
+}
+
+impl<T> GroupJob<T> {
+    fn finish(mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+    }
+}
+
+impl<T> Drop for GroupJob<T> {
+    fn drop(&mut self) {
+        let result = self.result.take().unwrap_or(Err(JoinError::Dropped));
+        let mut results = self.state.results.lock().unwrap();
+        results.slots[self.index] = Some(result);
+        results.pending -= 1;
+        if results.pending == 0 {
+            self.state.done.notify_all();
+        }
+    }
+}
+
+impl<T> GroupResult<T> {
+    /// Returns whether every job produced a value.
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(Result::is_ok)
+    }
+
+    /// Returns the number of jobs that panicked.
+    pub fn panicked(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches!(result, Err(JoinError::Panicked(_))))
+            .count()
+    }
+
+    /// Returns the result of every job, in the order they were submitted.
+    pub fn results(&self) -> &[Result<T, JoinError>] {
+        &self.results
+    }
+
+    /// Returns the result of every job, in the order they were submitted.
+    pub fn into_results(self) -> Vec<Result<T, JoinError>> {
+        self.results
+    }
+
+    /// Returns the values of every job, in the order they were submitted, or
+    /// the error of the first job that did not produce one.
+    pub fn into_values(self) -> Result<Vec<T>, JoinError> {
+        self.results.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::gated_job, JoinError, ThreadPool};
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn wait_collects_results_in_submission_order() {
+        let pool = ThreadPool::new(4);
+        let group = pool.task_group();
+        for i in 0..10u64 {
+            group.execute(move || {
+                thread::sleep(Duration::from_millis(10 - i));
+                i * i
+            });
+        }
+        assert_eq!(group.len(), 10);
+
+        let result = group.wait();
+        assert!(result.is_ok());
+        let squares: Vec<u64> = (0..10).map(|i| i * i).collect();
+        assert_eq!(result.into_values().unwrap(), squares);
+    }
+
+    #[test]
+    fn groups_aggregate_panics_and_do_not_wait_for_each_other() {
+        let pool = ThreadPool::new(2);
+        let (release, blocked) = gated_job();
+        let slow = pool.task_group();
+        slow.execute(blocked);
+
+        let fast = pool.task_group();
+        fast.execute(|| 1);
+        fast.execute(|| panic!("group job failed"));
+        fast.execute(|| 3);
+        let result = fast.wait();
+        assert!(!result.is_ok());
+        assert_eq!(result.panicked(), 1);
+        assert!(matches!(result.results()[1], Err(JoinError::Panicked(_))));
+        assert_eq!(result.results()[2].as_ref().unwrap(), &3);
+
+        drop(release);
+        assert!(slow.wait().is_ok());
+        pool.wait_idle();
+        assert_eq!(pool.stats().panicked(), 1);
+    }
+
+    #[test]
+    fn waiting_jobs_run_the_group_themselves() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let inner = Arc::clone(&pool);
+        let handle = pool.submit(move || {
+            let group = inner.task_group();
+            for i in 0..3 {
+                group.execute(move || i);
+            }
+            group.wait().into_values().unwrap()
+        });
+        let values = handle.join_timeout(Duration::from_secs(5));
+        assert_eq!(values.expect("group wait deadlocked").unwrap(), [0, 1, 2]);
+    }
+}
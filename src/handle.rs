@@ -0,0 +1,84 @@
+use std::{any::Any, error::Error, fmt, sync::mpsc, time::Duration};
+
+use crate::CancellationToken;
+
+/// A handle to a job submitted with [`ThreadPool::submit`](crate::ThreadPool::submit)
+/// or [`ThreadPool::submit_cancellable`](crate::ThreadPool::submit_cancellable).
+///
+/// The handle receives the value returned by the job once a worker has run it.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JoinError>>,
+    token: Option<CancellationToken>,
+}
+
+/// The reason a [`JobHandle`] could not produce the job's value.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The job panicked. Contains the panic payload.
+    Panicked(Box<dyn Any + Send>),
+    /// The job's [`CancellationToken`] was cancelled before the job started.
+    Cancelled,
+    /// The job was dropped before it produced a value, or its value has
+    /// already been taken from the handle.
+    Dropped,
+}
+
+impl<T> JobHandle<T> {
+    pub(crate) fn new(
+        receiver: mpsc::Receiver<Result<T, JoinError>>,
+        token: Option<CancellationToken>,
+    ) -> JobHandle<T> {
+        JobHandle { receiver, token }
+    }
+
+    /// Returns whether the job's [`CancellationToken`] has been cancelled.
+    /// Always false for jobs submitted without one.
+    ///
+    /// A job cancelled after it started may still finish and produce a
+    /// value.
+    pub fn is_cancelled(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Blocks until the job has finished and returns its value.
+    pub fn join(self) -> Result<T, JoinError> {
+        match self.receiver.recv() {
+            Ok(result) => result,
+            Err(_) => Err(JoinError::Dropped),
+        }
+    }
+
+    /// Returns the job's value if it has already finished, or `None` if it
+    /// is still queued or running.
+    pub fn try_join(&self) -> Option<Result<T, JoinError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JoinError::Dropped)),
+        }
+    }
+
+    /// Blocks for at most `timeout` waiting for the job to finish. Returns
+    /// `None` if the job is still queued or running when the timeout elapses.
+    pub fn join_timeout(&self, timeout: Duration) -> Option<Result<T, JoinError>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => Some(Err(JoinError::Dropped)),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(_) => write!(f, "job panicked"),
+            JoinError::Cancelled => write!(f, "job was cancelled before it started"),
+            JoinError::Dropped => write!(f, "job was dropped before producing a value"),
+        }
+    }
+}
+
+impl Error for JoinError {}
@@ -0,0 +1,193 @@
+//! Latency histograms.
+//!
+//! Durations are counted in nanoseconds in log-linear buckets, as in
+//! HdrHistogram: values below 32ns get a bucket each, and every power of two
+//! above that is split into 32 equal buckets, so any recorded value is known
+//! to within about 3%. Recording is a single atomic increment.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Sub-buckets per power of two, as a power of two.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Enough buckets for any `u64` number of nanoseconds.
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+pub(crate) struct Histogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+/// A copy of a latency histogram, as found in
+/// [`LatencyStats`](crate::LatencyStats).
+#[derive(Clone, Debug, Default)]
+pub struct HistogramSnapshot {
+    /// The non-empty buckets, by bucket index.
+    buckets: Vec<(usize, u64)>,
+    count: u64,
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub(crate) fn record(&self, value: Duration) {
+        let nanos = u64::try_from(value.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket_index(nanos)].fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Copies the histogram, emptying it if `reset` is set. Values recorded
+    /// while a reset is under way end up in either this snapshot or the next
+    /// one, never both.
+    pub(crate) fn snapshot(&self, reset: bool) -> HistogramSnapshot {
+        let buckets: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                if reset {
+                    bucket.swap(0, Ordering::SeqCst)
+                } else {
+                    bucket.load(Ordering::SeqCst)
+                }
+            })
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        let count = buckets.iter().map(|&(_, count)| count).sum();
+        HistogramSnapshot { buckets, count }
+    }
+}
+
+impl HistogramSnapshot {
+    /// Returns the number of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the value below which the fraction `quantile` of the recorded
+    /// values fall, e.g. `0.99` for the 99th percentile, rounded up to the
+    /// end of its bucket. Returns zero if the histogram is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `quantile` is not between 0 and 1.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        assert!(
+            (0.0..=1.0).contains(&quantile),
+            "quantile must be between 0 and 1"
+        );
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for &(index, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_max(index));
+            }
+        }
+        Duration::ZERO
+    }
+
+    pub fn p50(&self) -> Duration {
+        self.percentile(0.5)
+    }
+
+    pub fn p99(&self) -> Duration {
+        self.percentile(0.99)
+    }
+
+    pub fn p999(&self) -> Duration {
+        self.percentile(0.999)
+    }
+
+    /// Returns the largest recorded value, rounded up to the end of its
+    /// bucket.
+    pub fn max(&self) -> Duration {
+        self.percentile(1.0)
+    }
+
+    /// Returns the approximate sum of the recorded values, taking each as
+    /// the middle of its bucket.
+    pub fn sum(&self) -> Duration {
+        let nanos: u128 = self
+            .buckets
+            .iter()
+            .map(|&(index, count)| {
+                let middle = bucket_min(index) / 2 + bucket_max(index) / 2;
+                u128::from(middle) * u128::from(count)
+            })
+            .sum();
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// Returns the non-empty buckets as pairs of the largest value each
+    /// bucket holds and the number of values in it, in increasing order.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .map(|&(index, count)| (Duration::from_nanos(bucket_max(index)), count))
+    }
+}
+
+fn bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    // `nanos` lies in [2^exponent, 2^(exponent + 1)); its top bits pick the
+    // sub-bucket.
+    let exponent = 63 - nanos.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let top = (nanos >> shift) as usize;
+    (shift as usize + 1) * SUB_BUCKETS + top - SUB_BUCKETS
+}
+
+fn bucket_min(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    ((SUB_BUCKETS + index % SUB_BUCKETS) as u64) << shift
+}
+
+fn bucket_max(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    bucket_min(index) + ((1u64 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_every_value() {
+        for nanos in (0..10_000).chain([u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+            let index = bucket_index(nanos);
+            assert!(index < BUCKETS);
+            assert!(bucket_min(index) <= nanos, "{}", nanos);
+            assert!(nanos <= bucket_max(index), "{}", nanos);
+        }
+    }
+
+    #[test]
+    fn percentiles_are_within_bucket_precision() {
+        let histogram = Histogram::new();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        let snapshot = histogram.snapshot(true);
+        assert_eq!(snapshot.count(), 1000);
+        for (quantile, expected) in [(0.5, 500.0), (0.99, 990.0), (0.999, 999.0)] {
+            let micros = snapshot.percentile(quantile).as_secs_f64() * 1e6;
+            assert!((micros - expected).abs() / expected < 0.035, "{}", micros);
+        }
+        assert_eq!(histogram.snapshot(false).count(), 0);
+    }
+}
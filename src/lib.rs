@@ -1,81 +1,1214 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    io,
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+mod builder;
+mod cancel;
+mod error;
+mod group;
+mod handle;
+mod histogram;
+mod logging;
+mod parallel;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod queue;
+mod recurring;
+mod scheduler;
+mod scope;
+mod stats;
+mod timer;
+mod watchdog;
+
+pub use builder::{BuildError, ThreadPoolBuilder};
+pub use cancel::CancellationToken;
+pub use error::{PoolError, ShutdownTimedOut};
+pub use group::{GroupResult, TaskGroup};
+pub use handle::{JobHandle, JoinError};
+pub use histogram::HistogramSnapshot;
+#[cfg(feature = "prometheus")]
+pub use prometheus::MetricsServer;
+pub use queue::{Priority, RejectionPolicy};
+pub use recurring::{MissedTickPolicy, RecurringHandle};
+pub use scope::Scope;
+pub use stats::{LatencyStats, PoolStats, WorkerStats};
+pub use timer::TimerHandle;
+pub use watchdog::StuckJob;
+
+use logging::{debug, error, info, warning};
+use recurring::Schedule;
+use queue::{Admission, QueuedJob};
+use scheduler::{Pop, Scheduler};
+use stats::Stats;
+use timer::Timer;
+use watchdog::Watchdog;
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    workers: Arc<Mutex<Vec<Worker>>>,
+    shared: Arc<Shared>,
+    supervisor: Mutex<Option<thread::JoinHandle<()>>>,
+    /// Spawned on first use by [`ThreadPool::execute_at`].
+    timer: Mutex<Option<thread::JoinHandle<()>>>,
+    /// Spawned when the pool is built if jobs have a default timeout, and
+    /// otherwise on first use by [`ThreadPool::execute_with_timeout`].
+    watchdog: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+/// A queued job, as returned by [`ThreadPool::shutdown_now`].
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+type PanicHandler = Box<dyn Fn(Box<dyn Any + Send>) + Send + Sync + 'static>;
+
+/// State shared between the pool and its workers.
+struct Shared {
+    scheduler: Scheduler,
+    timer: Timer,
+    watchdog: Watchdog,
+    stats: Stats,
+    panic_handler: RwLock<Option<PanicHandler>>,
+    supervisor: mpsc::Sender<Event>,
+    restarts: AtomicUsize,
+    /// The number of workers the pool is meant to have, not counting extra
+    /// workers.
+    size: AtomicUsize,
+    /// The most workers an elastic pool may have, counting extra workers.
+    max_threads: Option<usize>,
+    /// Number of workers an elastic pool has beyond `size`, spawned while
+    /// every worker was busy.
+    extra: AtomicUsize,
+    /// How long extra workers wait for a job before exiting.
+    keep_alive: Duration,
+    /// The id given to the next worker that does not take over an existing
+    /// worker's id.
+    next_id: AtomicUsize,
+    /// Ids of workers that have exited and been joined, given to new workers
+    /// before fresh ids so per-worker state does not grow with every worker
+    /// the pool has ever had.
+    free_ids: Mutex<Vec<usize>>,
+    /// Number of worker threads that have been spawned and not yet exited.
+    alive: AtomicUsize,
+    /// Notified, under `exit_lock`, whenever a worker exits.
+    exited: Condvar,
+    exit_lock: Mutex<()>,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+}
+
+impl Shared {
+    /// Returns a thread builder configured with the pool's name prefix and
+    /// stack size. `suffix` distinguishes the pool's threads from each other.
+    fn thread_builder(&self, suffix: &str) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &self.thread_name {
+            builder = builder.name(format!("{}-{}", prefix, suffix));
+        }
+        if let Some(stack_size) = self.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        builder
+    }
+
+    /// Takes one worker off the count of extra workers, if there are any,
+    /// so that the calling worker may exit.
+    fn remove_extra_worker(&self) -> bool {
+        self.extra
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |extra| {
+                extra.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Returns an id for a new worker, reusing that of an exited worker if
+    /// there is one.
+    fn take_id(&self) -> usize {
+        let free = self.free_ids.lock().unwrap().pop();
+        free.unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Makes `id`, whose worker has exited or never started, available to
+    /// new workers.
+    fn release_id(&self, id: usize) {
+        self.free_ids.lock().unwrap().push(id);
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.stats
+            .snapshot(self.scheduler.pending(), self.alive.load(Ordering::SeqCst))
+    }
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+/// How long extra workers of an elastic pool wait for a job before exiting,
+/// unless configured otherwise.
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// How long the supervisor waits before retrying a worker it failed to respawn.
+const RESPAWN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn default_num_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Messages handled by the supervisor thread.
+enum Event {
+    /// The worker with this id exited abnormally and must be replaced.
+    Died(usize),
+    /// The worker with this id exited normally and must be joined.
+    Retired(usize),
+    /// The pool is shutting down; stop replacing workers.
+    Stop,
+}
 
 impl ThreadPool {
+    /// Creates a pool with `size` workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero or a worker thread cannot be spawned. Use
+    /// [`ThreadPoolBuilder`] to handle these errors instead.
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
-        println!("Starting threadpool with {} workers", size);
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        ThreadPoolBuilder::new()
+            .num_threads(size)
+            .build()
+            .expect("failed to build threadpool")
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<ThreadPool, BuildError> {
+        let size = builder.num_threads.unwrap_or_else(default_num_threads);
+        if size == 0 {
+            return Err(BuildError::ZeroThreads);
+        }
+        if builder.queue_capacity == Some(0) {
+            return Err(BuildError::ZeroQueueCapacity);
+        }
+        if builder.max_threads.is_some_and(|max| max < size) {
+            return Err(BuildError::MaxThreadsBelowCore);
+        }
+        info!("Starting threadpool with {} workers", size);
+        let (events, event_receiver) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            scheduler: Scheduler::new(
+                builder.queue_capacity,
+                builder.rejection_policy,
+                builder.priority_aging,
+            ),
+            timer: Timer::new(),
+            watchdog: Watchdog::new(
+                builder.job_timeout,
+                builder.stuck_job_handler,
+                builder.replace_stuck_workers,
+            ),
+            stats: Stats::new(),
+            panic_handler: RwLock::new(builder.panic_handler),
+            supervisor: events,
+            restarts: AtomicUsize::new(0),
+            size: AtomicUsize::new(size),
+            max_threads: builder.max_threads,
+            extra: AtomicUsize::new(0),
+            keep_alive: builder.keep_alive.unwrap_or(DEFAULT_KEEP_ALIVE),
+            next_id: AtomicUsize::new(size),
+            free_ids: Mutex::new(Vec::new()),
+            alive: AtomicUsize::new(0),
+            exited: Condvar::new(),
+            exit_lock: Mutex::new(()),
+            thread_name: builder.thread_name,
+            stack_size: builder.stack_size,
+        });
+
+        // Should spawning fail part way through, dropping the pool shuts down
+        // whatever has been started so far.
+        let mut pool = ThreadPool {
+            workers: Arc::new(Mutex::new(Vec::with_capacity(size))),
+            shared,
+            supervisor: Mutex::new(None),
+            timer: Mutex::new(None),
+            watchdog: Mutex::new(None),
+        };
+
+        *pool.supervisor.get_mut().unwrap() = Some(supervise(
+            Arc::clone(&pool.shared),
+            Arc::clone(&pool.workers),
+            event_receiver,
+        )?);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            let worker = Worker::new(id, Arc::clone(&pool.shared))?;
+            pool.workers.lock().unwrap().push(worker);
         }
-
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+        if pool.shared.watchdog.has_default_timeout() {
+            pool.start_watchdog()?;
         }
+
+        Ok(pool)
     }
 
+    /// Queues `f` to run on one of the pool's workers.
+    ///
+    /// If the queue is full, the pool's [`RejectionPolicy`] decides what
+    /// happens to `f`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool is shutting down, or if the queue is full and the
+    /// policy is [`RejectionPolicy::FailFast`]. Use
+    /// [`try_execute`](ThreadPool::try_execute) to handle these cases.
     pub fn execute<F>(&self, f: F)
         where
             F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.push(f, None);
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but queues `f` ahead of any
+    /// waiting jobs with a lower [`Priority`].
+    ///
+    /// Priorities order the pool's global queue. Jobs that a running job
+    /// submits with [`execute`](ThreadPool::execute) go to its worker's own
+    /// queue instead and are not ordered against prioritized jobs. See
+    /// [`ThreadPoolBuilder::priority_aging`] for keeping low-priority jobs from
+    /// starving.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        self.push(f, Some(priority));
+    }
+
+    fn push<F>(&self, f: F, priority: Option<Priority>)
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        match self.shared.scheduler.push(f, priority, Admission::Block) {
+            Ok(()) => self.grow_if_busy(),
+            Err(PoolError::QueueFull(f)) if self.caller_runs() => f(),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Queues `f`, a job of a scope, whatever the queue's capacity. Runs it
+    /// on the calling thread instead if the pool is shutting down.
+    pub(crate) fn execute_scoped<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        match self.shared.scheduler.push(f, None, Admission::Scoped) {
+            Ok(()) => self.grow_if_busy(),
+            Err(e) => e.into_inner()(),
+        }
+    }
+
+    /// Adds an extra worker to an elastic pool if there are more queued jobs
+    /// than idle workers to take them.
+    fn grow_if_busy(&self) {
+        let Some(max_threads) = self.shared.max_threads else {
+            return;
+        };
+        let scheduler = &self.shared.scheduler;
+        if scheduler.pending() <= scheduler.sleepers() {
+            return;
+        }
+        let mut workers = self.workers.lock().unwrap();
+        let live =
+            self.shared.size.load(Ordering::SeqCst) + self.shared.extra.load(Ordering::SeqCst);
+        if live >= max_threads || scheduler.is_closed() {
+            return;
+        }
+        let id = self.shared.take_id();
+        self.shared.extra.fetch_add(1, Ordering::SeqCst);
+        match Worker::new(id, Arc::clone(&self.shared)) {
+            Ok(worker) => {
+                debug!("Added worker {} to busy threadpool", id);
+                workers.push(worker);
+            }
+            Err(e) => {
+                self.shared.release_id(id);
+                self.shared.extra.fetch_sub(1, Ordering::SeqCst);
+                warning!("Failed to add worker to busy threadpool: {}", e);
+            }
+        }
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but returns an error holding
+    /// `f` instead of panicking if the pool cannot accept it. Never blocks:
+    /// with [`RejectionPolicy::Block`] a full queue is reported as
+    /// [`PoolError::QueueFull`].
+    pub fn try_execute<F>(&self, f: F) -> Result<(), PoolError<F>>
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        if self.shared.scheduler.is_closed() {
+            return Err(PoolError::ShutDown(f));
+        }
+        if self.shared.alive.load(Ordering::SeqCst) == 0 {
+            return Err(PoolError::NoWorkers(f));
+        }
+
+        match self.shared.scheduler.push(f, None, Admission::NoBlock) {
+            Ok(()) => {
+                self.grow_if_busy();
+                Ok(())
+            }
+            Err(PoolError::QueueFull(f)) if self.caller_runs() => {
+                f();
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Runs `f` on the pool once `delay` has elapsed. The returned handle can
+    /// cancel it until then.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool is shutting down or its timer thread cannot be
+    /// spawned.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> TimerHandle
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        self.execute_at(Instant::now() + delay, f)
+    }
+
+    /// Runs `f` on the pool at `instant`, or as soon as possible if it has
+    /// already passed. The returned handle can cancel it until then.
+    ///
+    /// Delayed jobs that are not yet due when the pool shuts down never run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool is shutting down or its timer thread cannot be
+    /// spawned.
+    pub fn execute_at<F>(&self, instant: Instant, f: F) -> TimerHandle
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        self.start_timer();
+        self.shared
+            .timer
+            .schedule(instant, Box::new(f))
+            .expect("threadpool is shutting down")
+    }
+
+    /// Runs `f` on the pool every `period`, starting once `initial_delay` has
+    /// elapsed. Ticks stay aligned to the first one however long each run
+    /// takes; `missed` decides what happens to ticks that pass while a run
+    /// is still going or waiting for a worker.
+    ///
+    /// Runs never overlap. If a run panics, the panic is reported to the
+    /// panic handler and the recurrence stops.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero, if the pool is shutting down, or if its
+    /// timer thread cannot be spawned.
+    pub fn schedule_fixed_rate<F>(
+        &self,
+        initial_delay: Duration,
+        period: Duration,
+        missed: MissedTickPolicy,
+        f: F,
+    ) -> RecurringHandle
+        where
+            F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!period.is_zero(), "period must be greater than zero");
+        self.schedule_recurring(initial_delay, Schedule::FixedRate(period, missed), f)
+    }
+
+    /// Runs `f` on the pool once `initial_delay` has elapsed, then again
+    /// `delay` after each run finishes.
+    ///
+    /// If a run panics, the panic is reported to the panic handler and the
+    /// recurrence stops.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool is shutting down or its timer thread cannot be
+    /// spawned.
+    pub fn schedule_fixed_delay<F>(
+        &self,
+        initial_delay: Duration,
+        delay: Duration,
+        f: F,
+    ) -> RecurringHandle
+        where
+            F: Fn() + Send + Sync + 'static,
+    {
+        self.schedule_recurring(initial_delay, Schedule::FixedDelay(delay), f)
+    }
+
+    fn schedule_recurring<F>(
+        &self,
+        initial_delay: Duration,
+        schedule: Schedule,
+        f: F,
+    ) -> RecurringHandle
+        where
+            F: Fn() + Send + Sync + 'static,
+    {
+        self.start_timer();
+        recurring::start(&self.shared, Instant::now() + initial_delay, schedule, f)
+            .expect("threadpool is shutting down")
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but reports `f` as stuck if it
+    /// runs for longer than `timeout`, overriding the pool's
+    /// [job timeout](ThreadPoolBuilder::job_timeout).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool is shutting down, if the queue is full and the
+    /// policy is [`RejectionPolicy::FailFast`], or if the watchdog thread
+    /// cannot be spawned.
+    pub fn execute_with_timeout<F>(&self, timeout: Duration, f: F)
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        self.start_watchdog()
+            .expect("failed to spawn watchdog thread");
+        self.execute(move || {
+            watchdog::set_timeout(timeout);
+            f()
+        });
+    }
+
+    fn start_watchdog(&self) -> io::Result<()> {
+        let mut watchdog = self.watchdog.lock().unwrap();
+        if watchdog.is_none() && !self.shared.scheduler.is_closed() {
+            let shared = Arc::clone(&self.shared);
+            let workers = Arc::clone(&self.workers);
+            let thread = self
+                .shared
+                .thread_builder("watchdog")
+                .spawn(move || watch(&shared, &workers))?;
+            self.shared.watchdog.enable();
+            *watchdog = Some(thread);
+        }
+        Ok(())
+    }
+
+    fn start_timer(&self) {
+        let mut timer = self.timer.lock().unwrap();
+        if timer.is_none() && !self.shared.scheduler.is_closed() {
+            let shared = Arc::clone(&self.shared);
+            let thread = self
+                .shared
+                .thread_builder("timer")
+                .spawn(move || shared.timer.run(&shared.scheduler))
+                .expect("failed to spawn timer thread");
+            *timer = Some(thread);
+        }
+    }
+
+    fn caller_runs(&self) -> bool {
+        self.shared.scheduler.policy() == RejectionPolicy::CallerRuns
+    }
+
+    /// Runs `f` on the pool and returns a [`JobHandle`] for its result.
+    ///
+    /// If `f` panics, the panic is caught and reported through the handle as
+    /// [`JoinError::Panicked`]; the panic handler is not called.
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+                stats::record_caught_panic();
+                JoinError::Panicked(payload)
+            });
+            let _ = sender.send(result);
+        });
+
+        JobHandle::new(receiver, None)
+    }
+
+    /// Like [`submit`](ThreadPool::submit), but skips `f` if `token` is
+    /// cancelled before a worker starts it, in which case the handle reports
+    /// [`JoinError::Cancelled`]. `f` receives the token so it can stop early
+    /// if it is cancelled while running.
+    pub fn submit_cancellable<F, T>(&self, token: &CancellationToken, f: F) -> JobHandle<T>
+        where
+            F: FnOnce(&CancellationToken) -> T + Send + 'static,
+            T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        let job_token = token.clone();
+        self.execute(move || {
+            let result = if job_token.is_cancelled() {
+                Err(JoinError::Cancelled)
+            } else {
+                panic::catch_unwind(AssertUnwindSafe(|| f(&job_token))).map_err(|payload| {
+                    stats::record_caught_panic();
+                    JoinError::Panicked(payload)
+                })
+            };
+            let _ = sender.send(result);
+        });
+
+        JobHandle::new(receiver, Some(token.clone()))
+    }
+
+    /// Runs `f` with a [`Scope`] whose jobs may borrow from the caller's
+    /// stack, and returns once `f` and every job it submitted have finished.
+    /// The jobs run on the pool's workers; no threads are spawned.
+    ///
+    /// If `f` or any of the jobs panics, `scope` panics with the first
+    /// payload once everything has finished.
+    ///
+    /// ```
+    /// use threadpool::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let mut halves = [vec![1, 2], vec![3, 4]];
+    /// pool.scope(|s| {
+    ///     for half in halves.iter_mut() {
+    ///         s.execute(move || half.iter_mut().for_each(|n| *n *= 10));
+    ///     }
+    /// });
+    /// assert_eq!(halves, [vec![10, 20], vec![30, 40]]);
+    /// ```
+    ///
+    /// Calling `scope` from a job is safe even when every worker does so:
+    /// while waiting, the job's worker runs queued jobs itself.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+        where
+            F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        scope::scope(self, f)
+    }
+
+    /// Applies `f` to every item on the pool's workers and returns the
+    /// results in the order of the items.
+    ///
+    /// The items are split into runs, one job each, that shrink towards the
+    /// end of the input so workers finish at about the same time. `f` may
+    /// borrow from the caller's stack, as in [`scope`](ThreadPool::scope).
+    ///
+    /// ```
+    /// use threadpool::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let offset = 1;
+    /// assert_eq!(pool.map(vec![1, 2, 3], |n| n * 2 + offset), [3, 5, 7]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, `map` panics with the same payload once every job has
+    /// finished.
+    pub fn map<I, F, R>(&self, items: I, f: F) -> Vec<R>
+        where
+            I: IntoIterator,
+            I::Item: Send,
+            F: Fn(I::Item) -> R + Sync,
+            R: Send,
+    {
+        parallel::map(self, items, f)
+    }
+
+    /// Runs `a` on the current thread and `b` on the pool, possibly in
+    /// parallel, and returns both results once both have finished. Both may
+    /// borrow from the caller's stack.
+    ///
+    /// When called from one of the pool's jobs, `b` is queued on the worker's
+    /// own deque, and the worker runs queued jobs, starting with `b` unless
+    /// another worker has stolen it, instead of blocking while it waits.
+    /// Recursive divide-and-conquer algorithms can therefore nest `join`
+    /// arbitrarily deep on any number of workers.
+    ///
+    /// ```
+    /// use threadpool::ThreadPool;
+    ///
+    /// fn sum(pool: &ThreadPool, numbers: &[u64]) -> u64 {
+    ///     if numbers.len() <= 1000 {
+    ///         return numbers.iter().sum();
+    ///     }
+    ///     let (left, right) = numbers.split_at(numbers.len() / 2);
+    ///     let (a, b) = pool.join(|| sum(pool, left), || sum(pool, right));
+    ///     a + b
+    /// }
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let numbers: Vec<u64> = (1..=100_000).collect();
+    /// assert_eq!(sum(&pool, &numbers), 5_000_050_000);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `a` or `b` panics, `join` panics with the same payload once both
+    /// have finished.
+    pub fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+        where
+            A: FnOnce() -> RA,
+            B: FnOnce() -> RB + Send,
+            RB: Send,
+    {
+        let mut b = Some(b);
+        let mut rb = None;
+        let ra = self.scope(|s| {
+            s.execute(|| rb = b.take().map(|b| b()));
+            a()
+        });
+        match (rb, b) {
+            (Some(rb), _) => (ra, rb),
+            // The job for `b` was discarded without running.
+            (None, Some(b)) => (ra, b()),
+            // The scope resumes the panic of a job that took `b` and did not
+            // finish, so one that took it has returned its result.
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// Maps every item with `map_fn` on the pool's workers and combines the
+    /// results with `reduce_fn`, returning `identity()` if there are none.
+    ///
+    /// Each job folds a run of items, split as in [`map`](ThreadPool::map),
+    /// into a partial result starting from `identity()`. The partial results
+    /// are then reduced in pairs, in parallel, until one is left. Items are
+    /// only ever combined with their neighbours, so `reduce_fn` needs to be
+    /// associative but not commutative, and `identity()` must leave any
+    /// value it is combined with unchanged.
+    ///
+    /// ```
+    /// use threadpool::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let words = ["fork", "join", "pool"];
+    /// let total = pool.map_reduce(words, |word| word.len(), |a, b| a + b, || 0);
+    /// assert_eq!(total, 12);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `map_fn` or `reduce_fn` panics, `map_reduce` panics with the same
+    /// payload once every job has finished.
+    pub fn map_reduce<I, M, R, ID, T>(&self, items: I, map_fn: M, reduce_fn: R, identity: ID) -> T
+        where
+            I: IntoIterator,
+            I::Item: Send,
+            M: Fn(I::Item) -> T + Sync,
+            R: Fn(T, T) -> T + Sync,
+            ID: Fn() -> T + Sync,
+            T: Send,
+    {
+        parallel::map_reduce(self, items, map_fn, reduce_fn, identity)
+    }
+
+    /// Calls `f` with every item of `items` on the pool's workers, split as
+    /// in [`map`](ThreadPool::map).
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, `for_each` panics with the same payload once every job
+    /// has finished.
+    pub fn for_each<T, F>(&self, items: &[T], f: F)
+        where
+            T: Sync,
+            F: Fn(&T) + Sync,
+    {
+        parallel::for_each(self, items, f)
+    }
+
+    /// Calls `f` with every `chunk_size` items of `items`, the last chunk
+    /// possibly shorter, on the pool's workers. Returns the results in the
+    /// order of the chunks. Chunks are grouped into jobs as in
+    /// [`map`](ThreadPool::map).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero. If `f` panics, `par_chunks` panics
+    /// with the same payload once every job has finished.
+    pub fn par_chunks<T, F, R>(&self, items: &[T], chunk_size: usize, f: F) -> Vec<R>
+        where
+            T: Sync,
+            F: Fn(&[T]) -> R + Sync,
+            R: Send,
+    {
+        parallel::par_chunks(self, items, chunk_size, f)
+    }
+
+    /// Creates an empty [`TaskGroup`] whose jobs run on this pool and can be
+    /// waited for together.
+    pub fn task_group<T>(&self) -> TaskGroup<'_, T>
+        where
+            T: Send + 'static,
+    {
+        TaskGroup::new(self)
+    }
+
+    /// Blocks until no jobs are queued and every worker is idle. The pool
+    /// keeps accepting jobs, from this thread once it returns and from
+    /// others in the meantime; jobs submitted while waiting are waited for
+    /// too. Delayed jobs that are not yet due are not.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from one of the pool's jobs, which would wait for
+    /// itself forever.
+    pub fn wait_idle(&self) {
+        self.assert_not_in_job();
+        self.shared.scheduler.wait_idle(None);
+    }
+
+    /// Like [`wait_idle`](ThreadPool::wait_idle), but gives up after
+    /// `timeout`. Returns whether the pool became idle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from one of the pool's jobs.
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        self.assert_not_in_job();
+        self.shared.scheduler.wait_idle(Some(Instant::now() + timeout))
+    }
+
+    fn assert_not_in_job(&self) {
+        assert!(
+            !self.shared.scheduler.is_worker_thread(),
+            "wait_idle called from one of the pool's own jobs"
+        );
+    }
+
+    /// Sets the handler called with the payload of any job passed to
+    /// [`execute`](ThreadPool::execute) that panics.
+    ///
+    /// Panicking jobs never take their worker down with them. Without a
+    /// handler the payload is dropped after the default panic hook has
+    /// reported it.
+    pub fn set_panic_handler<F>(&self, handler: F)
+        where
+            F: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        *self.shared.panic_handler.write().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Returns the number of workers the pool is meant to have. Workers that
+    /// are retiring after [`resize`](ThreadPool::resize) are not counted, nor
+    /// are the extra workers of an elastic pool.
+    pub fn num_threads(&self) -> usize {
+        self.shared.size.load(Ordering::SeqCst)
+    }
+
+    /// Changes the number of workers to `size`. For an elastic pool, this is
+    /// the number of core workers; the maximum is left as it is.
+    ///
+    /// New workers start straight away. Surplus workers exit the next time
+    /// they look for a job, so a busy worker finishes its current job first.
+    /// Does nothing once the pool is shutting down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn resize(&self, size: usize) -> io::Result<()> {
+        assert!(size > 0);
+        let mut workers = self.workers.lock().unwrap();
+        if self.shared.scheduler.is_closed() {
+            return Ok(());
+        }
+        let current = self.shared.size.load(Ordering::SeqCst);
+        debug!("Resizing threadpool from {} to {} workers", current, size);
+        if size < current {
+            self.shared.scheduler.retire(current - size);
+            self.shared.size.store(size, Ordering::SeqCst);
+            return Ok(());
+        }
+
+        // Workers that have not retired yet can simply stay.
+        let kept = self.shared.scheduler.unretire(size - current);
+        self.shared.size.fetch_add(kept, Ordering::SeqCst);
+        for _ in current + kept..size {
+            let id = self.shared.take_id();
+            let worker = Worker::new(id, Arc::clone(&self.shared))
+                .inspect_err(|_| self.shared.release_id(id))?;
+            workers.push(worker);
+            self.shared.size.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Returns how many workers have died and been replaced by the
+    /// supervisor since the pool was created.
+    pub fn restart_count(&self) -> usize {
+        self.shared.restarts.load(Ordering::SeqCst)
+    }
+
+    /// Returns a snapshot of the pool's activity.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+
+    /// Returns histograms of how long jobs have waited in the queue and how
+    /// long they have run since the pool was created, or since the last call
+    /// to [`take_latency`](ThreadPool::take_latency).
+    pub fn latency(&self) -> LatencyStats {
+        self.shared.stats.latency(false)
+    }
+
+    /// Like [`latency`](ThreadPool::latency), but also empties the
+    /// histograms, so that each call covers one reporting interval.
+    pub fn take_latency(&self) -> LatencyStats {
+        self.shared.stats.latency(true)
+    }
+
+    /// Renders the pool's statistics and latency histograms in the
+    /// Prometheus text exposition format. Metrics are prefixed with
+    /// `threadpool_` and, if the pool's threads are named, labelled with
+    /// `pool="{prefix}"`.
+    ///
+    /// The latency histograms are exported as summaries, which
+    /// [`take_latency`](ThreadPool::take_latency) resets.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_metrics(&self) -> String {
+        prometheus::render(&self.shared)
+    }
+
+    /// Serves [`prometheus_metrics`](ThreadPool::prometheus_metrics) at
+    /// `GET /metrics` on `addr`, from a thread of its own, until the returned
+    /// server is stopped or the pool has been dropped.
+    #[cfg(feature = "prometheus")]
+    pub fn serve_metrics<A>(&self, addr: A) -> io::Result<MetricsServer>
+        where
+            A: std::net::ToSocketAddrs,
+    {
+        prometheus::serve(&self.shared, addr)
+    }
+
+    /// Returns how many jobs the watchdog has reported as running for longer
+    /// than their timeout since the pool was created.
+    pub fn stuck_job_count(&self) -> usize {
+        self.shared.watchdog.stuck_count()
+    }
+
+    /// Stops accepting jobs, waits for every queued job to run and joins the
+    /// workers. Dropping the pool does the same.
+    ///
+    /// Jobs submitted after shutdown has begun are rejected with
+    /// [`PoolError::ShutDown`]. Calling this more than once is harmless.
+    pub fn shutdown(&self) {
+        self.stop_timer();
+        self.shared.scheduler.close();
+        debug!("Gracefully slaughtering workers...");
+        self.stop_supervisor();
+        self.stop_watchdog();
+        self.join_workers(|_| true);
+    }
+
+    /// Stops accepting jobs, discards the queued jobs and joins the workers
+    /// once they finish the jobs they are running. Returns the discarded jobs,
+    /// including delayed jobs that were not yet due.
+    pub fn shutdown_now(&self) -> Vec<Job> {
+        // Joining the timer thread first lands a delayed job it is handing
+        // over in the queue, where the drain finds it.
+        let delayed = self.stop_timer();
+        let mut pending = self.shared.scheduler.drain();
+        pending.extend(delayed);
+        debug!("Discarded {} queued jobs, slaughtering workers...", pending.len());
+        self.stop_supervisor();
+        self.stop_watchdog();
+        self.join_workers(|_| true);
+        pending
+    }
+
+    /// Like [`shutdown`](ThreadPool::shutdown), but gives up waiting after
+    /// `timeout`.
+    ///
+    /// If some workers are still running jobs when the timeout elapses, they
+    /// are detached from the pool and their ids are returned in the error.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Result<(), ShutdownTimedOut> {
+        let deadline = Instant::now() + timeout;
+        self.stop_timer();
+        self.shared.scheduler.close();
+        debug!("Gracefully slaughtering workers within {:?}...", timeout);
+        self.stop_supervisor();
+        self.stop_watchdog();
+
+        let mut guard = self.shared.exit_lock.lock().unwrap();
+        while self.shared.alive.load(Ordering::SeqCst) > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            guard = self.shared.exited.wait_timeout(guard, deadline - now).unwrap().0;
+        }
+        drop(guard);
+
+        let unfinished = self.join_workers(|thread| thread.is_finished());
+        if unfinished.is_empty() {
+            Ok(())
+        } else {
+            warning!("Workers {:?} did not finish before the shutdown timeout", unfinished);
+            Err(ShutdownTimedOut::new(unfinished))
+        }
+    }
+
+    /// Stops the timer thread and returns the delayed jobs it was holding.
+    fn stop_timer(&self) -> Vec<Job> {
+        let pending = self.shared.timer.stop();
+        if let Some(timer) = self.timer.lock().unwrap().take() {
+            let _ = timer.join();
+        }
+        pending
+    }
+
+    fn stop_watchdog(&self) {
+        self.shared.watchdog.stop();
+        if let Some(watchdog) = self.watchdog.lock().unwrap().take() {
+            let _ = watchdog.join();
+        }
+    }
+
+    fn stop_supervisor(&self) {
+        let _ = self.shared.supervisor.send(Event::Stop);
+        if let Some(supervisor) = self.supervisor.lock().unwrap().take() {
+            let _ = supervisor.join();
+        }
+    }
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    /// Removes every worker from the pool, joining those for which `join`
+    /// returns true and detaching the rest. Returns the ids of the detached
+    /// workers.
+    fn join_workers<P>(&self, join: P) -> Vec<usize>
+        where
+            P: Fn(&thread::JoinHandle<()>) -> bool,
+    {
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        let mut detached = Vec::new();
+        for worker in workers {
+            let Some(thread) = worker.thread else {
+                continue;
+            };
+            // A job shutting down its own pool must not wait for itself.
+            if thread.thread().id() == thread::current().id() {
+                continue;
+            }
+            if join(&thread) {
+                let _ = thread.join();
+            } else {
+                detached.push(worker.id);
+            }
+        }
+        detached
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
-        println!("Gracefully slaughtering workers...");
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        self.shutdown();
+    }
+}
+
+/// Spawns the supervisor thread, which replaces workers that die so the pool
+/// keeps its configured size, and joins workers that retire.
+fn supervise(
+    shared: Arc<Shared>,
+    workers: Arc<Mutex<Vec<Worker>>>,
+    events: mpsc::Receiver<Event>,
+) -> io::Result<thread::JoinHandle<()>> {
+    shared.thread_builder("supervisor").spawn(move || loop {
+        match events.recv() {
+            Ok(Event::Died(id)) => restart(&shared, &workers, id),
+            Ok(Event::Retired(id)) => {
+                let mut workers = workers.lock().unwrap();
+                let retired = workers.iter().position(|worker| worker.id == id);
+                let retired = retired.map(|index| workers.remove(index));
+                drop(workers);
+                if let Some(worker) = retired {
+                    if let Some(thread) = worker.thread {
+                        let _ = thread.join();
+                    }
+                    shared.release_id(id);
+                }
+            }
+            Ok(Event::Stop) | Err(_) => break,
+        }
+    })
+}
+
+/// Replaces the dead worker `id`.
+fn restart(shared: &Arc<Shared>, workers: &Mutex<Vec<Worker>>, id: usize) {
+    let mut workers = workers.lock().unwrap();
+    if let Some(worker) = workers.iter_mut().find(|worker| worker.id == id) {
+        if let Some(thread) = worker.thread.take() {
+            let _ = thread.join();
+        }
+        warning!("Restarting worker {}", id);
+        // Count the restart before the replacement can pick up jobs.
+        shared.restarts.fetch_add(1, Ordering::SeqCst);
+        match Worker::new(id, Arc::clone(shared)) {
+            Ok(replacement) => *worker = replacement,
+            Err(e) => {
+                error!("Failed to restart worker {}: {}", id, e);
+                shared.restarts.fetch_sub(1, Ordering::SeqCst);
+                drop(workers);
+                thread::sleep(RESPAWN_RETRY_DELAY);
+                let _ = shared.supervisor.send(Event::Died(id));
+            }
+        }
+    }
+}
+
+/// Runs the watchdog thread, which reports stuck jobs and, if configured,
+/// spawns a replacement for each worker running one.
+fn watch(shared: &Arc<Shared>, workers: &Mutex<Vec<Worker>>) {
+    while let Some(stuck) = shared.watchdog.wait() {
+        for job in stuck {
+            shared.watchdog.report(&job);
+            if !shared.watchdog.replaces_stuck_workers() || !shared.watchdog.retire(&job) {
+                continue;
+            }
+            let mut workers = workers.lock().unwrap();
+            // Shutting down takes the workers; a replacement would be leaked.
+            if shared.scheduler.is_closed() {
+                shared.watchdog.unretire(&job);
+                continue;
+            }
+            let (id, stuck) = (shared.take_id(), job.worker());
+            match Worker::new(id, Arc::clone(shared)) {
+                Ok(replacement) => {
+                    info!("Spawned worker {} to replace stuck worker {}", id, stuck);
+                    workers.push(replacement);
+                }
+                Err(e) => {
+                    shared.release_id(id);
+                    error!("Failed to replace stuck worker {}: {}", stuck, e);
+                    if !shared.watchdog.unretire(&job) {
+                        error!("Worker {} has exited; the pool is one worker short", stuck);
+                    }
+                }
             }
         }
     }
 }
 
 struct Worker {
+    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
+/// Runs a queued job on the current thread while it waits for other jobs,
+/// if the thread is one of `shared`'s workers. Returns whether there was a
+/// job to run.
+///
+/// The job runs nested inside the worker's current job, which the watchdog
+/// keeps tracking in its place. If the job panics and so does the panic
+/// handler, the handler's panic is returned instead, so the waiting job can
+/// finish waiting before it unwinds.
+fn help(shared: &Shared) -> thread::Result<bool> {
+    let Some((id, QueuedJob { job, queued, .. })) = shared.scheduler.pop_for_current() else {
+        return Ok(false);
+    };
+    let _span = logging::job_span();
+    let counters = shared.stats.counters(id);
+    let result = shared.stats.run_nested(&counters, queued, job);
+    shared.scheduler.finish();
+    if let Err(payload) = result {
+        report_panic(shared, id, payload)?;
+    }
+    Ok(true)
+}
+
+/// Logs that a job panicked on worker `id` and passes its payload to the
+/// panic handler. Returns the handler's own panic, if it panics.
+fn report_panic(shared: &Shared, id: usize, payload: Box<dyn Any + Send>) -> thread::Result<()> {
+    warning!("Job panicked on worker {}", id);
+    match shared.panic_handler.read().unwrap().as_ref() {
+        Some(handler) => panic::catch_unwind(AssertUnwindSafe(|| handler(payload))),
+        None => Ok(()),
+    }
+}
+
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {} is dead", id);
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let builder = shared.thread_builder(&id.to_string());
+        // Counted before spawning so the worker is never missed by a caller
+        // checking for live workers; the sentinel undoes this on exit.
+        shared.alive.fetch_add(1, Ordering::SeqCst);
+        let worker_shared = Arc::clone(&shared);
+        let thread = builder
+            .spawn(move || Worker::run(id, &worker_shared))
+            .inspect_err(|_| {
+                shared.alive.fetch_sub(1, Ordering::SeqCst);
+            })?;
+
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(id: usize, shared: &Shared) {
+        let _sentinel = Sentinel { id, shared };
+        let _span = logging::worker_span(id);
+        shared.scheduler.register(id);
+        let slot = shared.watchdog.register(id);
+        let counters = shared.stats.register(id);
+        let idle_timeout = shared.max_threads.map(|_| shared.keep_alive);
+        loop {
+            let QueuedJob { job, queued, .. } = match shared.scheduler.pop(id, idle_timeout) {
+                Pop::Job(job) => job,
+                Pop::TimedOut if shared.remove_extra_worker() => {
+                    debug!("Worker {} was idle for {:?} and is exiting", id, shared.keep_alive);
                     break;
                 }
+                Pop::TimedOut => continue,
+                Pop::Exit => break,
+            };
+            let _span = logging::job_span();
+            shared.watchdog.start(&slot);
+            let started = shared.stats.start(queued);
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            shared.stats.finish(&counters, started, result.is_err());
+            shared.scheduler.finish();
+            if let Err(payload) = result {
+                // A panicking handler takes the worker down, to be restarted
+                // by the supervisor.
+                if let Err(panic) = report_panic(shared, id, payload) {
+                    panic::resume_unwind(panic);
+                }
+            }
+            if shared.watchdog.finish(&slot) {
+                debug!("Worker {} was replaced while stuck and is exiting", id);
+                break;
             }
-        });
-
-        Worker {
-            thread: Some(thread),
         }
+        debug!("Worker {} is dead", id);
     }
 }
 
+/// Lives on a worker's stack, marks the worker as exited and tells the
+/// supervisor whether it unwound.
+struct Sentinel<'a> {
+    id: usize,
+    shared: &'a Shared,
+}
+
+impl Drop for Sentinel<'_> {
+    fn drop(&mut self) {
+        self.shared.alive.fetch_sub(1, Ordering::SeqCst);
+        drop(self.shared.exit_lock.lock().unwrap());
+        self.shared.exited.notify_all();
+        let event = if thread::panicking() {
+            Event::Died(self.id)
+        } else {
+            Event::Retired(self.id)
+        };
+        let _ = self.shared.supervisor.send(event);
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -93,10 +1226,12 @@ mod tests {
     const FILENAME: &str = "test.file";
     const THREADS: usize = 4;
 
+    #[allow(clippy::let_unit_value)]
     fn create_file() {
         let _ = fs::write(FILENAME, vec![]).unwrap();
     }
 
+    #[allow(clippy::ineffective_open_options)]
     fn add_to_file(val: u8) {
         println!("Writing {} to file", val);
 
@@ -117,7 +1252,57 @@ mod tests {
         Ok(io::BufReader::new(file).lines())
     }
 
+    /// Returns a job that blocks until the returned sender is dropped or sent
+    /// to.
+    pub(crate) fn gated_job() -> (mpsc::Sender<()>, impl FnOnce() + Send + 'static) {
+        let (release, gate) = mpsc::channel::<()>();
+        (release, move || {
+            let _ = gate.recv();
+        })
+    }
+
+    /// Occupies one of `pool`'s workers until the returned sender is dropped
+    /// or sent to.
+    pub(crate) fn occupy_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (started, wait_started) = mpsc::channel();
+        let (release, job) = gated_job();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            job();
+        });
+        wait_started.recv().unwrap();
+        release
+    }
+
+    /// Waits until all but the pool's core workers have exited and `freed`
+    /// ids are available for reuse.
+    fn wait_for_exits(pool: &ThreadPool, freed: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.workers.lock().unwrap().len() > pool.num_threads()
+            || pool.shared.free_ids.lock().unwrap().len() < freed
+        {
+            assert!(Instant::now() < deadline, "surplus workers did not exit");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Runs `count` jobs on `pool` that only finish once all of them are
+    /// running at once, and waits for them.
+    pub(crate) fn run_at_once(pool: &ThreadPool, count: usize) {
+        let barrier = Arc::new(std::sync::Barrier::new(count));
+        let handles: Vec<_> = (0..count)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.submit(move || barrier.wait())
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join_timeout(Duration::from_secs(5)).is_some());
+        }
+    }
+
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn it_works() {
         create_file();
 
@@ -134,9 +1319,8 @@ mod tests {
             add_to_file(0 as u8);
         });
 
-        drop(threadpool);
+        threadpool.wait_idle();
 
-        thread::sleep(Duration::from_secs(1));
         let lines = read_lines(FILENAME).expect("failed to read");
         for (i, line) in lines.enumerate() {
             match line {
@@ -156,4 +1340,184 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn submit_returns_result() {
+        let threadpool = ThreadPool::new(THREADS);
+
+        let handles: Vec<_> = (0..8u64).map(|i| threadpool.submit(move || i * i)).collect();
+        let results: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec![0, 1, 4, 9, 16, 25, 36, 49]);
+
+        let slow = threadpool.submit(|| {
+            thread::sleep(Duration::from_millis(200));
+            "done"
+        });
+        assert!(slow.try_join().is_none());
+        assert!(slow.join_timeout(Duration::from_millis(10)).is_none());
+        assert_eq!(slow.join_timeout(Duration::from_secs(5)).unwrap().unwrap(), "done");
+    }
+
+    #[test]
+    fn panicking_jobs_do_not_kill_workers() {
+        let threadpool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        threadpool.set_panic_handler(move |payload| {
+            let message = payload.downcast_ref::<&str>().copied().unwrap_or_default();
+            sender.send(message.to_string()).unwrap();
+        });
+
+        threadpool.execute(|| panic!("boom"));
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), "boom");
+
+        match threadpool.submit(|| -> u8 { panic!("bang") }).join() {
+            Err(JoinError::Panicked(payload)) => {
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"bang"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // The single worker survived both panics.
+        assert_eq!(threadpool.submit(|| 42).join().unwrap(), 42);
+        assert_eq!(threadpool.restart_count(), 0);
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        let threadpool = ThreadPool::new(1);
+        // A panicking handler takes the worker down with it.
+        threadpool.set_panic_handler(|_| panic!("handler failed"));
+
+        threadpool.execute(|| panic!("boom"));
+
+        assert_eq!(threadpool.submit(|| 42).join().unwrap(), 42);
+        assert_eq!(threadpool.restart_count(), 1);
+    }
+
+    #[test]
+    fn shutdown_runs_queued_jobs() {
+        let threadpool = ThreadPool::new(2);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            threadpool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        threadpool.shutdown();
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+        assert!(matches!(threadpool.try_execute(|| {}), Err(PoolError::ShutDown(_))));
+    }
+
+    #[test]
+    fn shutdown_now_returns_queued_jobs() {
+        let threadpool = ThreadPool::new(1);
+        let release = occupy_worker(&threadpool);
+        for _ in 0..3 {
+            threadpool.execute(|| {});
+        }
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(release);
+        });
+        assert_eq!(threadpool.shutdown_now().len(), 3);
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_timeout_reports_stuck_workers() {
+        let threadpool = ThreadPool::new(2);
+        let release = occupy_worker(&threadpool);
+
+        let error = threadpool
+            .shutdown_timeout(Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(error.workers().len(), 1);
+        drop(release);
+    }
+
+    #[test]
+    fn try_execute_runs_job() {
+        let threadpool = ThreadPool::new(THREADS);
+        let (sender, receiver) = mpsc::channel();
+
+        threadpool
+            .try_execute(move || sender.send(7).unwrap())
+            .unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), 7);
+    }
+
+    #[test]
+    fn wait_idle_keeps_the_pool_usable() {
+        let threadpool = ThreadPool::new(2);
+        let release = occupy_worker(&threadpool);
+        assert!(!threadpool.wait_idle_timeout(Duration::from_millis(50)));
+        drop(release);
+        assert!(threadpool.wait_idle_timeout(Duration::from_secs(5)));
+
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let done = Arc::clone(&done);
+            threadpool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        threadpool.wait_idle();
+        assert_eq!(done.load(Ordering::SeqCst), 20);
+        assert_eq!(threadpool.stats().active(), 0);
+
+        let threadpool = Arc::new(threadpool);
+        let inner = Arc::clone(&threadpool);
+        let waited = threadpool.submit(move || inner.wait_idle_timeout(Duration::from_secs(5)));
+        assert!(matches!(waited.join(), Err(JoinError::Panicked(_))));
+    }
+
+    #[test]
+    fn resize_grows_and_shrinks() {
+        let threadpool = ThreadPool::new(1);
+        threadpool.resize(3).unwrap();
+        assert_eq!(threadpool.num_threads(), 3);
+        run_at_once(&threadpool, 3);
+
+        threadpool.resize(1).unwrap();
+        assert_eq!(threadpool.num_threads(), 1);
+        wait_for_exits(&threadpool, 2);
+        assert_eq!(threadpool.shared.alive.load(Ordering::SeqCst), 1);
+        assert_eq!(threadpool.submit(|| 5).join().unwrap(), 5);
+
+        // Workers added later take over the ids of those that retired.
+        for _ in 0..5 {
+            threadpool.resize(3).unwrap();
+            threadpool.resize(1).unwrap();
+            wait_for_exits(&threadpool, 2);
+        }
+        assert_eq!(threadpool.stats().workers().len(), 3);
+    }
+
+    #[test]
+    fn elastic_pool_grows_under_load_and_shrinks_when_idle() {
+        let threadpool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .max_threads(3)
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        run_at_once(&threadpool, 3);
+        assert_eq!(threadpool.workers.lock().unwrap().len(), 3);
+
+        wait_for_exits(&threadpool, 2);
+        assert_eq!(threadpool.num_threads(), 1);
+
+        // Later extra workers take over the ids of those that exited.
+        for _ in 0..5 {
+            run_at_once(&threadpool, 3);
+            wait_for_exits(&threadpool, 2);
+        }
+        assert_eq!(threadpool.stats().workers().len(), 3);
+    }
 }
@@ -0,0 +1,62 @@
+//! Lifecycle logging.
+//!
+//! Events go to `tracing` when the `tracing` feature is enabled, otherwise to
+//! `log` when the `log` feature is enabled. With neither feature the pool is
+//! silent.
+
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($arg)+);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        ::log::$level!($($arg)+);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::logging::event!(debug, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::logging::event!(info, $($arg)+) };
+}
+
+// Not named `warn`, which would clash with the built-in lint attribute.
+macro_rules! warning {
+    ($($arg:tt)+) => { $crate::logging::event!(warn, $($arg)+) };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::logging::event!(error, $($arg)+) };
+}
+
+pub(crate) use {debug, error, event, info, warning};
+
+/// Keeps a span entered for as long as it is alive.
+#[cfg(feature = "tracing")]
+pub(crate) type SpanGuard = tracing::span::EnteredSpan;
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct SpanGuard;
+
+/// Enters the span covering the whole life of worker `id`.
+pub(crate) fn worker_span(id: usize) -> SpanGuard {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!("worker", id).entered();
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = id;
+        SpanGuard
+    }
+}
+
+/// Enters the span covering a single job.
+pub(crate) fn job_span() -> SpanGuard {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!("job").entered();
+    #[cfg(not(feature = "tracing"))]
+    SpanGuard
+}
@@ -0,0 +1,241 @@
+//! Data-parallel helpers.
+//!
+//! The input is cut into runs that become jobs of a [`Scope`](crate::Scope),
+//! so the helpers borrow freely from the caller and spawn no threads. Runs
+//! start large and shrink towards the end of the input, as in guided
+//! self-scheduling: the large ones keep the number of jobs low, and the small
+//! ones even out workers that finish their earlier runs at different times.
+
+use crate::ThreadPool;
+
+pub(crate) fn map<I, F, R>(pool: &ThreadPool, items: I, f: F) -> Vec<R>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> R + Sync,
+        R: Send,
+{
+    let items: Vec<_> = items.into_iter().collect();
+    let mut results = Vec::with_capacity(items.len());
+    let outputs = map_runs(pool, items, |run| run.into_iter().map(&f).collect::<Vec<_>>());
+    outputs.into_iter().for_each(|output| results.extend(output));
+    results
+}
+
+pub(crate) fn map_reduce<I, M, R, ID, T>(
+    pool: &ThreadPool,
+    items: I,
+    map_fn: M,
+    reduce_fn: R,
+    identity: ID,
+) -> T
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        M: Fn(I::Item) -> T + Sync,
+        R: Fn(T, T) -> T + Sync,
+        ID: Fn() -> T + Sync,
+        T: Send,
+{
+    let items: Vec<_> = items.into_iter().collect();
+    let mut partials = map_runs(pool, items, |run| {
+        run.into_iter()
+            .fold(identity(), |acc, item| reduce_fn(acc, map_fn(item)))
+    });
+    // Neighbours are reduced in pairs, keeping the order of the items, until
+    // one value is left.
+    while partials.len() > 1 {
+        let mut rest = partials.into_iter();
+        let mut pairs = Vec::new();
+        while let Some(left) = rest.next() {
+            pairs.push((left, rest.next()));
+        }
+        partials = map(pool, pairs, |(left, right)| match right {
+            Some(right) => reduce_fn(left, right),
+            None => left,
+        });
+    }
+    partials.pop().unwrap_or_else(identity)
+}
+
+/// Splits `items` into runs and calls `f` with each run on a job of its own.
+/// Returns the results in the order of the runs.
+fn map_runs<T, F, R>(pool: &ThreadPool, items: Vec<T>, f: F) -> Vec<R>
+    where
+        T: Send,
+        F: Fn(Vec<T>) -> R + Sync,
+        R: Send,
+{
+    let mut items = items.into_iter();
+    let mut runs: Vec<Option<Vec<T>>> = run_lengths(items.len(), pool.num_threads())
+        .into_iter()
+        .map(|length| Some(items.by_ref().take(length).collect()))
+        .collect();
+    let mut outputs: Vec<Option<R>> = runs.iter().map(|_| None).collect();
+
+    let f = &f;
+    pool.scope(|s| {
+        for (run, output) in runs.iter_mut().zip(&mut outputs) {
+            s.execute(move || *output = run.take().map(f));
+        }
+    });
+    runs.into_iter()
+        .zip(outputs)
+        .map(|(run, output)| match (output, run) {
+            (Some(output), _) => output,
+            // The job for the run was discarded without running.
+            (None, Some(run)) => f(run),
+            // The scope resumes the panic of a job that took its run and did
+            // not finish, so one that took it has returned its output.
+            (None, None) => unreachable!(),
+        })
+        .collect()
+}
+
+pub(crate) fn for_each<T, F>(pool: &ThreadPool, items: &[T], f: F)
+    where
+        T: Sync,
+        F: Fn(&T) + Sync,
+{
+    let mut rest = items;
+    let mut runs: Vec<(&[T], bool)> = run_lengths(items.len(), pool.num_threads())
+        .into_iter()
+        .map(|length| {
+            let (run, tail) = rest.split_at(length);
+            rest = tail;
+            (run, false)
+        })
+        .collect();
+
+    let f = &f;
+    pool.scope(|s| {
+        for (run, done) in &mut runs {
+            let run = *run;
+            s.execute(move || {
+                run.iter().for_each(f);
+                *done = true;
+            });
+        }
+    });
+    // Runs whose jobs were discarded without running.
+    for (run, _) in runs.into_iter().filter(|&(_, done)| !done) {
+        run.iter().for_each(f);
+    }
+}
+
+pub(crate) fn par_chunks<T, F, R>(pool: &ThreadPool, items: &[T], chunk_size: usize, f: F) -> Vec<R>
+    where
+        T: Sync,
+        F: Fn(&[T]) -> R + Sync,
+        R: Send,
+{
+    assert!(chunk_size > 0, "chunk size must be non-zero");
+    map(pool, items.chunks(chunk_size), f)
+}
+
+/// Splits `len` items into runs for `threads` workers, each run taking a
+/// share of what the previous ones left.
+fn run_lengths(len: usize, threads: usize) -> Vec<usize> {
+    let mut lengths = Vec::new();
+    let mut remaining = len;
+    while remaining > 0 {
+        let length = (remaining / (2 * threads)).max(1);
+        lengths.push(length);
+        remaining -= length;
+    }
+    lengths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_lengths;
+    use crate::{tests::occupy_worker, RejectionPolicy, ThreadPool, ThreadPoolBuilder};
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Mutex,
+        },
+        thread,
+    };
+
+    #[test]
+    fn runs_cover_the_input_and_shrink() {
+        for (len, threads) in [(0, 4), (1, 4), (7, 1), (1000, 3), (100_000, 16)] {
+            let lengths = run_lengths(len, threads);
+            assert_eq!(lengths.iter().sum::<usize>(), len);
+            assert!(lengths.windows(2).all(|pair| pair[0] >= pair[1]));
+        }
+        assert!(run_lengths(100_000, 4).len() < 200);
+    }
+
+    #[test]
+    fn map_preserves_order_across_workers() {
+        let pool = ThreadPool::new(4);
+        let threads = Mutex::new(HashSet::new());
+
+        let squares = pool.map(0..10_000u64, |n| {
+            threads.lock().unwrap().insert(thread::current().id());
+            n * n
+        });
+
+        assert_eq!(squares, (0..10_000).map(|n| n * n).collect::<Vec<_>>());
+        assert!(!threads.lock().unwrap().contains(&thread::current().id()));
+    }
+
+    #[test]
+    fn for_each_and_par_chunks_visit_every_item() {
+        let pool = ThreadPool::new(3);
+        let numbers: Vec<usize> = (1..=1000).collect();
+
+        let total = AtomicUsize::new(0);
+        pool.for_each(&numbers, |&n| {
+            total.fetch_add(n, Ordering::SeqCst);
+        });
+        assert_eq!(total.load(Ordering::SeqCst), 500_500);
+
+        let sums = pool.par_chunks(&numbers, 100, |chunk| chunk.iter().sum::<usize>());
+        assert_eq!(sums.len(), 10);
+        assert_eq!(sums[0], 5050);
+        assert_eq!(sums.iter().sum::<usize>(), 500_500);
+    }
+
+    #[test]
+    fn map_reduce_keeps_the_order_of_the_items() {
+        let pool = ThreadPool::new(4);
+
+        let sum = pool.map_reduce(1..=1000u64, |n| n * n, |a, b| a + b, || 0);
+        assert_eq!(sum, 333_833_500);
+
+        let words: Vec<String> = (0..500).map(|n| n.to_string()).collect();
+        let joined = pool.map_reduce(&words, |word| word.clone(), |a, b| a + &b, String::new);
+        assert_eq!(joined, words.concat());
+
+        let empty: Vec<u64> = Vec::new();
+        assert_eq!(pool.map_reduce(empty, |n| n, |a, b| a + b, || 7), 7);
+    }
+
+    #[test]
+    fn map_reduce_under_a_full_drop_oldest_queue() {
+        let builder = ThreadPoolBuilder::new()
+            .num_threads(2)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::DropOldest);
+        let pool = builder.build().unwrap();
+        let _release = occupy_worker(&pool);
+        let done = AtomicBool::new(false);
+
+        let sum = thread::scope(|threads| {
+            // Keeps the queue full, evicting the oldest job all along.
+            threads.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    pool.execute(|| {});
+                }
+            });
+            let sum = pool.map_reduce(1..=1000u64, |n| n * n, |a, b| a + b, || 0);
+            done.store(true, Ordering::SeqCst);
+            sum
+        });
+        assert_eq!(sum, 333_833_500);
+    }
+}
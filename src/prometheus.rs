@@ -0,0 +1,292 @@
+//! Prometheus exposition of the pool's statistics.
+//!
+//! [`render`] writes the text format by hand and [`serve`] answers scrapes
+//! with a minimal HTTP/1.1 server on a thread of its own, so the feature
+//! needs no dependencies.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    logging::{debug, warning},
+    HistogramSnapshot, Shared,
+};
+
+/// How long the server waits for a scraper to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The summary quantiles exported for each latency histogram.
+const QUANTILES: [f64; 3] = [0.5, 0.99, 0.999];
+
+/// A running metrics endpoint, as returned by
+/// [`ThreadPool::serve_metrics`](crate::ThreadPool::serve_metrics).
+///
+/// Dropping the handle leaves the endpoint running. Once the pool has been
+/// dropped, the endpoint closes on the next connection.
+#[derive(Debug)]
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Returns the address the endpoint is listening on, which tells the
+    /// port picked when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the endpoint and waits for its thread to exit.
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the thread from `accept`.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Renders the pool's statistics in the Prometheus text exposition format.
+pub(crate) fn render(shared: &Shared) -> String {
+    let stats = shared.stats();
+    let latency = shared.stats.latency(false);
+    let labels = match &shared.thread_name {
+        Some(name) => format!("pool=\"{}\"", escape(name)),
+        None => String::new(),
+    };
+    let with = |extra: &str| match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => String::new(),
+        (true, false) => format!("{{{}}}", extra),
+        (false, true) => format!("{{{}}}", labels),
+        (false, false) => format!("{{{},{}}}", labels, extra),
+    };
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+        let _ = writeln!(out, "# HELP threadpool_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE threadpool_{} {}", name, kind);
+        for (suffix, value) in samples {
+            let _ = writeln!(out, "threadpool_{}{} {}", name, suffix, value);
+        }
+    };
+
+    metric(
+        "jobs_queued",
+        "gauge",
+        "Jobs waiting for a worker.",
+        &[(with(""), stats.queued().to_string())],
+    );
+    metric(
+        "workers_active",
+        "gauge",
+        "Workers running a job.",
+        &[(with(""), stats.active().to_string())],
+    );
+    metric(
+        "workers_idle",
+        "gauge",
+        "Live workers waiting for a job.",
+        &[(with(""), stats.idle().to_string())],
+    );
+    metric(
+        "jobs_completed_total",
+        "counter",
+        "Jobs that finished without panicking.",
+        &[(with(""), stats.completed().to_string())],
+    );
+    metric(
+        "jobs_panicked_total",
+        "counter",
+        "Jobs that panicked.",
+        &[(with(""), stats.panicked().to_string())],
+    );
+    metric(
+        "jobs_stuck_total",
+        "counter",
+        "Jobs that ran for longer than their timeout.",
+        &[(with(""), shared.watchdog.stuck_count().to_string())],
+    );
+    metric(
+        "worker_restarts_total",
+        "counter",
+        "Workers that died and were replaced.",
+        &[(with(""), shared.restarts.load(Ordering::SeqCst).to_string())],
+    );
+    let busy: Vec<_> = stats
+        .workers()
+        .iter()
+        .map(|worker| {
+            let label = with(&format!("worker=\"{}\"", worker.id()));
+            (label, worker.busy().as_secs_f64().to_string())
+        })
+        .collect();
+    metric(
+        "worker_busy_seconds_total",
+        "counter",
+        "Time each worker has spent running jobs.",
+        &busy,
+    );
+    metric(
+        "job_queue_wait_seconds",
+        "summary",
+        "Time from a job being queued to a worker starting it.",
+        &summary(latency.queue_wait(), with),
+    );
+    metric(
+        "job_execution_seconds",
+        "summary",
+        "Time workers spent running jobs.",
+        &summary(latency.execution(), with),
+    );
+    out
+}
+
+fn summary<L>(histogram: &HistogramSnapshot, with: L) -> Vec<(String, String)>
+    where
+        L: Fn(&str) -> String,
+{
+    let mut samples: Vec<_> = QUANTILES
+        .iter()
+        .map(|&quantile| {
+            let label = with(&format!("quantile=\"{}\"", quantile));
+            let value = histogram.percentile(quantile).as_secs_f64();
+            (label, value.to_string())
+        })
+        .collect();
+    let labels = with("");
+    samples.push((
+        format!("_sum{}", labels),
+        histogram.sum().as_secs_f64().to_string(),
+    ));
+    samples.push((format!("_count{}", labels), histogram.count().to_string()));
+    samples
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Binds `addr` and answers `GET /metrics` on a new thread until the
+/// returned server is stopped or the pool has been dropped.
+pub(crate) fn serve<A>(shared: &Arc<Shared>, addr: A) -> io::Result<MetricsServer>
+    where
+        A: ToSocketAddrs,
+{
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let pool = Arc::downgrade(shared);
+    let thread_stopped = Arc::clone(&stopped);
+    let thread = shared
+        .thread_builder("metrics")
+        .spawn(move || accept(&listener, &pool, &thread_stopped))?;
+    debug!("Serving metrics on {}", addr);
+    Ok(MetricsServer {
+        addr,
+        stopped,
+        thread: Some(thread),
+    })
+}
+
+fn accept(listener: &TcpListener, pool: &Weak<Shared>, stopped: &AtomicBool) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let Some(shared) = pool.upgrade() else {
+            break;
+        };
+        let result = stream.and_then(|stream| respond(stream, &shared));
+        // Dropped before waiting for the next scrape so the pool is not kept
+        // alive by the endpoint.
+        drop(shared);
+        if let Err(e) = result {
+            warning!("Failed to serve metrics: {}", e);
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers; a GET has no body.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(shared)),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ThreadPoolBuilder;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name("scraped")
+            .build()
+            .unwrap();
+        pool.execute(|| ());
+        // Settles the counters; the endpoint keeps serving them.
+        pool.shutdown();
+        let server = pool.serve_metrics("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        for line in [
+            "# TYPE threadpool_jobs_completed_total counter\n",
+            "threadpool_jobs_completed_total{pool=\"scraped\"} 1\n",
+            "threadpool_job_execution_seconds_count{pool=\"scraped\"} 1\n",
+            "threadpool_worker_busy_seconds_total{pool=\"scraped\",worker=\"1\"} ",
+        ] {
+            assert!(response.contains(line), "{}", response);
+        }
+        server.stop();
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{Job, PoolError};
+
+/// The priority of a job waiting in the pool's queue. Higher priorities run
+/// first; jobs of equal priority run in the order they were submitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority(pub u8);
+
+impl Priority {
+    pub const LOW: Priority = Priority(0);
+    /// The priority of jobs submitted without one.
+    pub const NORMAL: Priority = Priority(1);
+    pub const HIGH: Priority = Priority(2);
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::NORMAL
+    }
+}
+
+/// What happens to a job submitted while the pool's queue is full.
+///
+/// The policy never applies to jobs that bypass the queue's capacity; see
+/// [`ThreadPoolBuilder::queue_capacity`](crate::ThreadPoolBuilder::queue_capacity).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RejectionPolicy {
+    /// Block the submitter until there is room in the queue.
+    #[default]
+    Block,
+    /// Reject the job. [`ThreadPool::execute`](crate::ThreadPool::execute)
+    /// panics and [`ThreadPool::try_execute`](crate::ThreadPool::try_execute)
+    /// returns [`PoolError::QueueFull`].
+    FailFast,
+    /// Discard the job that has been queued the longest to make room. Jobs
+    /// of a [scope](crate::ThreadPool::scope) are never discarded.
+    DropOldest,
+    /// Run the job on the submitter's thread instead of queueing it.
+    CallerRuns,
+}
+
+/// How [`JobQueue::push`] treats a job that finds the queue full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Admission {
+    /// Apply the rejection policy, blocking the submitter under
+    /// [`RejectionPolicy::Block`].
+    Block,
+    /// Apply the rejection policy, but report [`PoolError::QueueFull`]
+    /// instead of blocking.
+    NoBlock,
+    /// Queue the job regardless of the capacity, for jobs the pool hands to
+    /// itself, which have no submitter to block or to hand back to.
+    Bypass,
+    /// Like `Bypass`, for a job of a scope. The scope waits for the job to
+    /// run, so once queued it is never discarded.
+    Scoped,
+}
+
+/// A job waiting for a worker, and when it started waiting.
+pub(crate) struct QueuedJob {
+    pub(crate) job: Job,
+    pub(crate) queued: Instant,
+    /// Whether the job was queued with [`Admission::Scoped`].
+    pub(crate) scoped: bool,
+}
+
+impl QueuedJob {
+    pub(crate) fn new(job: Job, admission: Admission) -> QueuedJob {
+        QueuedJob {
+            job,
+            queued: Instant::now(),
+            scoped: admission == Admission::Scoped,
+        }
+    }
+}
+
+/// The global priority queue of jobs submitted from outside the pool,
+/// optionally bounded. Waking idle workers is left to the
+/// [`Scheduler`](crate::scheduler::Scheduler).
+pub(crate) struct JobQueue {
+    state: Mutex<QueueState>,
+    not_full: Condvar,
+    /// Only set while holding the `state` lock, so pushes see it under the
+    /// lock, but readable without taking it.
+    closed: AtomicBool,
+    capacity: Option<usize>,
+    policy: RejectionPolicy,
+    /// Waiting this long raises a job's effective priority by one level.
+    aging: Option<Duration>,
+    epoch: Instant,
+}
+
+struct QueueState {
+    /// One queue per priority in use, highest priority first.
+    lanes: Vec<Lane>,
+    len: usize,
+    next_seq: u64,
+    /// Number of submitters waiting on `not_full`, which only needs
+    /// notifying, a system call, when there are some.
+    blocked: usize,
+}
+
+/// The jobs of one priority, in submission order. A job's rank never comes
+/// before the rank of a job of the same priority queued earlier, so the job
+/// to run next is always at the front of one of the lanes.
+struct Lane {
+    priority: Priority,
+    jobs: VecDeque<Entry>,
+}
+
+/// A queued job. The lowest `rank` is handed out first, then the lowest
+/// `seq`, so equal ranks keep submission order.
+struct Entry {
+    rank: i128,
+    seq: u64,
+    job: QueuedJob,
+}
+
+impl JobQueue {
+    pub(crate) fn new(
+        capacity: Option<usize>,
+        policy: RejectionPolicy,
+        aging: Option<Duration>,
+    ) -> JobQueue {
+        JobQueue {
+            state: Mutex::new(QueueState {
+                lanes: Vec::new(),
+                len: 0,
+                next_seq: 0,
+                blocked: 0,
+            }),
+            not_full: Condvar::new(),
+            closed: AtomicBool::new(false),
+            capacity,
+            policy,
+            aging,
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Without aging, a job's rank is its negated priority. With aging, it is
+    /// the time the job was queued, moved earlier by one aging interval per
+    /// priority level: comparing two such ranks gives the same answer as
+    /// comparing priorities that grow by one level per interval waited,
+    /// without the ranks ever having to change.
+    fn rank(&self, priority: Priority, queued: Instant) -> i128 {
+        let level = i128::from(priority.0);
+        match self.aging {
+            None => -level,
+            Some(interval) => {
+                let waited = queued.duration_since(self.epoch);
+                waited.as_nanos() as i128 - level * interval.as_nanos() as i128
+            }
+        }
+    }
+
+    pub(crate) fn policy(&self) -> RejectionPolicy {
+        self.policy
+    }
+
+    /// Queues `f`, applying the rejection policy if the queue is full.
+    ///
+    /// `f` is only boxed once it has been accepted, so it can be handed back
+    /// inside the error otherwise. [`RejectionPolicy::CallerRuns`] is left to
+    /// the caller, which receives [`PoolError::QueueFull`]. See [`Admission`]
+    /// for how `admission` changes this.
+    ///
+    /// Returns the job discarded to make room, if any. It should be dropped
+    /// without holding any locks: dropping a job can run arbitrary code.
+    pub(crate) fn push<F>(
+        &self,
+        f: F,
+        priority: Priority,
+        admission: Admission,
+    ) -> Result<Option<Job>, PoolError<F>>
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = None;
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(PoolError::ShutDown(f));
+            }
+            if matches!(admission, Admission::Bypass | Admission::Scoped)
+                || self
+                    .capacity
+                    .is_none_or(|capacity| state.len < capacity)
+            {
+                break;
+            }
+            match self.policy {
+                RejectionPolicy::Block if admission == Admission::Block => {
+                    state.blocked += 1;
+                    state = self.not_full.wait(state).unwrap();
+                    state.blocked -= 1;
+                }
+                RejectionPolicy::DropOldest => {
+                    // With only scoped jobs queued, nothing can make room,
+                    // and the job is queued beyond the capacity like them.
+                    dropped = state.remove_oldest();
+                    break;
+                }
+                _ => return Err(PoolError::QueueFull(f)),
+            }
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        // Taken under the lock, so later jobs never have earlier times.
+        let job = QueuedJob::new(Box::new(f), admission);
+        let entry = Entry {
+            rank: self.rank(priority, job.queued),
+            seq,
+            job,
+        };
+        state.lane(priority).jobs.push_back(entry);
+        state.len += 1;
+        Ok(dropped)
+    }
+
+    /// Returns the queued job with the highest effective priority, if any.
+    /// Jobs are still handed out after the queue has been closed.
+    pub(crate) fn try_pop(&self) -> Option<QueuedJob> {
+        let mut state = self.state.lock().unwrap();
+        let job = state.pop().map(|entry| entry.job);
+        let notify = job.is_some() && state.blocked > 0;
+        drop(state);
+        if notify {
+            self.not_full.notify_one();
+        }
+        job
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Closes the queue and returns the jobs still waiting in it, in the
+    /// order they would have run. Scoped jobs stay queued.
+    pub(crate) fn drain(&self) -> Vec<Job> {
+        let mut state = self.state.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        let mut discarded = Vec::new();
+        for lane in &mut state.lanes {
+            let (kept, dropped): (VecDeque<_>, VecDeque<_>) =
+                lane.jobs.drain(..).partition(|entry| entry.job.scoped);
+            lane.jobs = kept;
+            discarded.extend(dropped);
+        }
+        state.len -= discarded.len();
+        drop(state);
+        discarded.sort_by_key(|entry| (entry.rank, entry.seq));
+        let jobs = discarded.into_iter().map(|entry| entry.job.job).collect();
+        self.not_full.notify_all();
+        jobs
+    }
+
+    /// Stops the queue accepting jobs. Jobs already queued are still handed
+    /// out by [`try_pop`](JobQueue::try_pop).
+    pub(crate) fn close(&self) {
+        let state = self.state.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        drop(state);
+        self.not_full.notify_all();
+    }
+}
+
+impl QueueState {
+    /// Returns the lane of `priority`, adding it if there is none yet.
+    fn lane(&mut self, priority: Priority) -> &mut Lane {
+        let index = match self
+            .lanes
+            .binary_search_by(|lane| priority.cmp(&lane.priority))
+        {
+            Ok(index) => index,
+            Err(index) => {
+                let lane = Lane {
+                    priority,
+                    jobs: VecDeque::new(),
+                };
+                self.lanes.insert(index, lane);
+                index
+            }
+        };
+        &mut self.lanes[index]
+    }
+
+    /// Removes the job with the lowest rank, then the lowest `seq`, which is
+    /// at the front of its lane.
+    fn pop(&mut self) -> Option<Entry> {
+        let lane = self
+            .lanes
+            .iter_mut()
+            .filter_map(|lane| {
+                let front = lane.jobs.front()?;
+                Some(((front.rank, front.seq), lane))
+            })
+            .min_by_key(|&(key, _)| key)?
+            .1;
+        let entry = lane.jobs.pop_front();
+        self.len -= 1;
+        entry
+    }
+
+    /// Removes the job that was queued first, regardless of its priority,
+    /// passing over scoped jobs.
+    fn remove_oldest(&mut self) -> Option<Job> {
+        let (_, lane, index) = self
+            .lanes
+            .iter()
+            .enumerate()
+            .filter_map(|(lane, Lane { jobs, .. })| {
+                let index = jobs.iter().position(|entry| !entry.job.scoped)?;
+                Some((jobs[index].seq, lane, index))
+            })
+            .min()?;
+        let entry = self.lanes[lane].jobs.remove(index)?;
+        self.len -= 1;
+        Some(entry.job.job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        tests::occupy_worker, JoinError, PoolError, Priority, RejectionPolicy, ThreadPool,
+        ThreadPoolBuilder,
+    };
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// Builds a single worker pool with a queue of one slot and occupies the
+    /// worker until the returned sender is dropped or sent to.
+    fn blocked_pool(policy: RejectionPolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let builder = ThreadPoolBuilder::new()
+            .queue_capacity(1)
+            .rejection_policy(policy);
+        block(builder)
+    }
+
+    /// Builds a single worker pool and occupies the worker until the returned
+    /// sender is dropped or sent to.
+    fn block(builder: ThreadPoolBuilder) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = builder.num_threads(1).build().unwrap();
+        let release = occupy_worker(&pool);
+        (pool, release)
+    }
+
+    #[test]
+    fn fail_fast_rejects_when_full() {
+        let (pool, release) = blocked_pool(RejectionPolicy::FailFast);
+        pool.try_execute(|| {}).unwrap();
+
+        let rejected = pool.try_execute(|| {});
+        assert!(matches!(rejected, Err(PoolError::QueueFull(_))));
+        drop(release);
+    }
+
+    #[test]
+    fn nested_jobs_bypass_the_capacity() {
+        let builder = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::FailFast);
+        let pool = Arc::new(builder.build().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            for _ in 0..3 {
+                sender.send(inner.try_execute(|| {}).is_ok()).unwrap();
+            }
+        });
+
+        let accepted: Vec<_> = receiver.iter().collect();
+        assert_eq!(accepted, [true; 3]);
+        pool.wait_idle();
+    }
+
+    #[test]
+    fn drop_oldest_discards_queued_job() {
+        let (pool, release) = blocked_pool(RejectionPolicy::DropOldest);
+        let oldest = pool.submit(|| 1);
+        let newest = pool.submit(|| 2);
+        drop(release);
+
+        assert!(matches!(oldest.join(), Err(JoinError::Dropped)));
+        assert_eq!(newest.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn caller_runs_when_full() {
+        let (pool, release) = blocked_pool(RejectionPolicy::CallerRuns);
+        pool.execute(|| {});
+
+        let caller = thread::current().id();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().id()).unwrap());
+        assert_eq!(receiver.try_recv().unwrap(), caller);
+        drop(release);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let (pool, release) = blocked_pool(RejectionPolicy::Block);
+        pool.execute(|| {});
+        assert!(matches!(
+            pool.try_execute(|| {}),
+            Err(PoolError::QueueFull(_))
+        ));
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            release.send(()).unwrap();
+        });
+        let start = Instant::now();
+        pool.execute(|| {});
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn higher_priorities_run_first() {
+        let (pool, release) = block(ThreadPoolBuilder::new());
+        let (sender, receiver) = mpsc::channel();
+        for (name, priority) in [
+            ("low", Priority::LOW),
+            ("normal", Priority::NORMAL),
+            ("high", Priority::HIGH),
+            ("normal again", Priority::NORMAL),
+        ] {
+            let sender = sender.clone();
+            pool.execute_with_priority(priority, move || sender.send(name).unwrap());
+        }
+        drop(release);
+
+        let order: Vec<_> = (0..4).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(order, ["high", "normal", "normal again", "low"]);
+    }
+
+    #[test]
+    fn aging_lets_waiting_jobs_overtake() {
+        let builder = ThreadPoolBuilder::new().priority_aging(Duration::from_millis(1));
+        let (pool, release) = block(builder);
+        let (sender, receiver) = mpsc::channel();
+        let low = sender.clone();
+        pool.execute_with_priority(Priority::LOW, move || low.send("low").unwrap());
+        thread::sleep(Duration::from_millis(50));
+        pool.execute_with_priority(Priority::HIGH, move || sender.send("high").unwrap());
+        drop(release);
+
+        let order: Vec<_> = (0..2).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(order, ["low", "high"]);
+    }
+}
@@ -0,0 +1,304 @@
+//! Recurring jobs.
+//!
+//! A recurring job is a chain of delayed jobs: each run, once finished,
+//! schedules the next one on the pool's timer. Runs of the same job therefore
+//! never overlap and never hold a worker between runs.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{Shared, TimerHandle};
+
+/// What a fixed-rate job does about ticks it missed because a run, or the
+/// wait for a free worker, took longer than its period.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissedTickPolicy {
+    /// Run once for every missed tick, back to back, until the job has
+    /// caught up with its schedule.
+    CatchUp,
+    /// Drop the missed ticks and wait for the next tick on the original
+    /// schedule.
+    #[default]
+    Skip,
+    /// Run once straight away in place of all the missed ticks, then carry
+    /// on with the original schedule.
+    Coalesce,
+}
+
+/// A handle to a job scheduled with
+/// [`ThreadPool::schedule_fixed_rate`](crate::ThreadPool::schedule_fixed_rate)
+/// or
+/// [`ThreadPool::schedule_fixed_delay`](crate::ThreadPool::schedule_fixed_delay).
+#[derive(Clone, Debug)]
+pub struct RecurringHandle {
+    control: Arc<Control>,
+}
+
+impl RecurringHandle {
+    /// Stops the recurrence. A run already in progress is allowed to finish.
+    pub fn cancel(&self) {
+        let mut next = self.control.next.lock().unwrap();
+        self.control.stopped.store(true, Ordering::SeqCst);
+        if let Some(next) = next.take() {
+            next.cancel();
+        }
+    }
+
+    /// Returns whether the recurrence has stopped, because it was cancelled,
+    /// a run panicked, or the pool shut down.
+    pub fn is_cancelled(&self) -> bool {
+        self.control.stopped.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Control {
+    stopped: AtomicBool,
+    /// The delayed job for the next run.
+    next: Mutex<Option<TimerHandle>>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Schedule {
+    FixedRate(Duration, MissedTickPolicy),
+    FixedDelay(Duration),
+}
+
+struct Recurrence<F> {
+    f: F,
+    schedule: Schedule,
+    shared: Weak<Shared>,
+    control: Arc<Control>,
+}
+
+/// The delayed job for the run of `tick`. The pool discards delayed jobs by
+/// dropping them, so dropping one that has not run stops the recurrence.
+struct Tick<F> {
+    recurrence: Option<Arc<Recurrence<F>>>,
+    tick: Instant,
+}
+
+/// Schedules the first run of `f` for `first`. Returns `None` if the pool's
+/// timer has been stopped.
+pub(crate) fn start<F>(
+    shared: &Arc<Shared>,
+    first: Instant,
+    schedule: Schedule,
+    f: F,
+) -> Option<RecurringHandle>
+    where
+        F: Fn() + Send + Sync + 'static,
+{
+    let recurrence = Arc::new(Recurrence {
+        f,
+        schedule,
+        shared: Arc::downgrade(shared),
+        control: Arc::default(),
+    });
+    let control = Arc::clone(&recurrence.control);
+    recurrence
+        .arm(first, first)
+        .then_some(RecurringHandle { control })
+}
+
+impl<F> Recurrence<F>
+    where
+        F: Fn() + Send + Sync + 'static,
+{
+    /// Schedules the run for `tick` at `due`. The two differ only when
+    /// coalescing missed ticks. Returns false, and stops the recurrence, if
+    /// the pool's timer has been stopped.
+    fn arm(self: Arc<Self>, tick: Instant, due: Instant) -> bool {
+        let Some(shared) = self.shared.upgrade() else {
+            self.control.stopped.store(true, Ordering::SeqCst);
+            return false;
+        };
+        let control = Arc::clone(&self.control);
+        let mut next = control.next.lock().unwrap();
+        if control.stopped.load(Ordering::SeqCst) {
+            return true;
+        }
+        let tick = Tick {
+            recurrence: Some(self),
+            tick,
+        };
+        *next = shared.timer.schedule(due, Box::new(move || tick.run()));
+        if next.is_none() {
+            control.stopped.store(true, Ordering::SeqCst);
+        }
+        next.is_some()
+    }
+
+    fn run(self: Arc<Self>, tick: Instant) {
+        if self.control.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(&self.f)) {
+            self.control.stopped.store(true, Ordering::SeqCst);
+            panic::resume_unwind(payload);
+        }
+
+        let now = Instant::now();
+        let (tick, due) = match self.schedule {
+            Schedule::FixedDelay(delay) => (now + delay, now + delay),
+            Schedule::FixedRate(period, policy) => next_tick(tick, period, policy, now),
+        };
+        self.arm(tick, due);
+    }
+}
+
+impl<F> Tick<F>
+    where
+        F: Fn() + Send + Sync + 'static,
+{
+    fn run(mut self) {
+        if let Some(recurrence) = self.recurrence.take() {
+            recurrence.run(self.tick);
+        }
+    }
+}
+
+impl<F> Drop for Tick<F> {
+    fn drop(&mut self) {
+        if let Some(recurrence) = self.recurrence.take() {
+            recurrence.control.stopped.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Returns the tick after `tick` for a fixed-rate job finishing a run at
+/// `now`, and when that run is due.
+fn next_tick(
+    tick: Instant,
+    period: Duration,
+    policy: MissedTickPolicy,
+    now: Instant,
+) -> (Instant, Instant) {
+    let next = tick + period;
+    if next > now {
+        return (next, next);
+    }
+    // The last tick that is already due, found without multiplying the
+    // period by the number of missed ticks, which may be huge.
+    let overshoot = (now - next).as_nanos() % period.as_nanos();
+    let last = now - Duration::from_nanos(u64::try_from(overshoot).unwrap_or(u64::MAX));
+    match policy {
+        MissedTickPolicy::CatchUp => (next, next),
+        MissedTickPolicy::Skip => (last + period, last + period),
+        MissedTickPolicy::Coalesce => (last, now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::occupy_worker, RejectionPolicy, ThreadPool, ThreadPoolBuilder};
+    use std::{sync::atomic::AtomicUsize, thread};
+
+    #[test]
+    fn missed_ticks() {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // On time: the next tick is one period on.
+        let on_time = next_tick(start, period, MissedTickPolicy::Skip, at(4));
+        assert_eq!(on_time, (at(10), at(10)));
+
+        // Finished at 35ms, so the ticks at 10, 20 and 30ms were missed.
+        let catch_up = next_tick(start, period, MissedTickPolicy::CatchUp, at(35));
+        assert_eq!(catch_up, (at(10), at(10)));
+        let skip = next_tick(start, period, MissedTickPolicy::Skip, at(35));
+        assert_eq!(skip, (at(40), at(40)));
+        let coalesce = next_tick(start, period, MissedTickPolicy::Coalesce, at(35));
+        assert_eq!(coalesce, (at(30), at(35)));
+
+        // A microsecond period stalled for longer than 2^32 periods.
+        let period = Duration::from_micros(1);
+        let stalled = start + Duration::from_secs(5000) + Duration::from_nanos(300);
+        let skip = next_tick(start, period, MissedTickPolicy::Skip, stalled);
+        assert_eq!(skip.0, start + Duration::from_secs(5000) + period);
+        let coalesce = next_tick(start, period, MissedTickPolicy::Coalesce, stalled);
+        assert_eq!(coalesce, (start + Duration::from_secs(5000), stalled));
+    }
+
+    #[test]
+    fn fixed_rate_runs_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&runs);
+        let handle = pool.schedule_fixed_rate(
+            Duration::ZERO,
+            Duration::from_millis(20),
+            MissedTickPolicy::Skip,
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        thread::sleep(Duration::from_millis(150));
+        handle.cancel();
+        assert!(handle.is_cancelled());
+
+        let after_cancel = runs.load(Ordering::SeqCst);
+        assert!(after_cancel >= 3, "only {} runs", after_cancel);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+    }
+
+    #[test]
+    fn fixed_delay_waits_after_each_run() {
+        let pool = ThreadPool::new(2);
+        let starts = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = Arc::clone(&starts);
+        let delay = Duration::from_millis(20);
+        let handle = pool.schedule_fixed_delay(Duration::ZERO, delay, move || {
+            recorded.lock().unwrap().push(Instant::now());
+            thread::sleep(Duration::from_millis(20));
+        });
+        thread::sleep(Duration::from_millis(150));
+        handle.cancel();
+
+        let starts = starts.lock().unwrap();
+        assert!(starts.len() >= 2);
+        for pair in starts.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(40));
+        }
+    }
+
+    #[test]
+    fn discarded_runs_stop_the_recurrence() {
+        let builder = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::FailFast);
+        let pool = builder.build().unwrap();
+        let release = occupy_worker(&pool);
+        pool.execute(|| {});
+
+        // Due while the queue is full, and still runs.
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        let handle = pool.schedule_fixed_delay(Duration::ZERO, Duration::from_millis(1), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(release);
+        while runs.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!handle.is_cancelled());
+
+        let later = pool.schedule_fixed_delay(Duration::from_secs(3600), Duration::ZERO, || {});
+        drop(pool.shutdown_now());
+        assert!(handle.is_cancelled());
+        assert!(later.is_cancelled());
+    }
+}
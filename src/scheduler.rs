@@ -0,0 +1,427 @@
+//! Work-stealing scheduling.
+//!
+//! Jobs submitted from outside the pool go to a global injector queue. Jobs
+//! submitted by a job running on one of the pool's workers go to that
+//! worker's own deque instead, so workers rarely contend on a single lock.
+//! Each worker takes jobs from the back of its own deque first, then from the
+//! injector, and finally steals from the front of the other workers' deques.
+
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    queue::{Admission, JobQueue, QueuedJob},
+    Job, PoolError, Priority, RejectionPolicy,
+};
+
+/// How many times a worker that finds no job yields before it goes to sleep.
+/// Waking a sleeping worker takes a system call on both sides, which costs
+/// more than a job usually does.
+const SPINS: usize = 16;
+
+thread_local! {
+    /// The scheduler, worker id and deque of the pool worker running on this
+    /// thread.
+    static CURRENT: Cell<Option<Current>> = const { Cell::new(None) };
+}
+
+type Current = (*const Scheduler, usize, *const LocalQueue);
+
+type LocalQueue = Mutex<VecDeque<QueuedJob>>;
+
+/// The outcome of [`Scheduler::pop`].
+pub(crate) enum Pop {
+    Job(QueuedJob),
+    /// The worker waited for a job for longer than its idle timeout.
+    TimedOut,
+    /// The worker has been asked to retire, or the scheduler is closed and
+    /// every queue is empty.
+    Exit,
+}
+
+pub(crate) struct Scheduler {
+    injector: JobQueue,
+    /// Per-worker deques, indexed by worker id.
+    locals: RwLock<Vec<Arc<LocalQueue>>>,
+    /// Number of jobs waiting in the injector and all local deques.
+    pending: AtomicUsize,
+    /// Number of workers asleep, or about to fall asleep, on `wake`.
+    sleepers: AtomicUsize,
+    /// Number of workers asked to exit that have not done so yet.
+    retiring: AtomicUsize,
+    /// Number of jobs handed to workers that have not finished yet,
+    /// including jobs run by workers waiting in the middle of another job.
+    /// Only tells [`wait_idle`](Scheduler::wait_idle) when the pool is idle;
+    /// the pool's stats count busy workers themselves.
+    running: AtomicUsize,
+    /// Notified, under `idle_lock`, when the last running job finishes with
+    /// no jobs pending and `idle_waiters` is non-zero.
+    idle: Condvar,
+    idle_lock: Mutex<()>,
+    idle_waiters: AtomicUsize,
+    /// Guards sleeping on `wake`, and counts the notifications sent on it
+    /// that no worker has woken up for yet. `sleepers` only changes under
+    /// this lock, and is never below the count.
+    sleep_lock: Mutex<usize>,
+    wake: Condvar,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        capacity: Option<usize>,
+        policy: RejectionPolicy,
+        aging: Option<Duration>,
+    ) -> Scheduler {
+        Scheduler {
+            injector: JobQueue::new(capacity, policy, aging),
+            locals: RwLock::new(Vec::new()),
+            pending: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            retiring: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            idle: Condvar::new(),
+            idle_lock: Mutex::new(()),
+            idle_waiters: AtomicUsize::new(0),
+            sleep_lock: Mutex::new(0),
+            wake: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn policy(&self) -> RejectionPolicy {
+        self.injector.policy()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.injector.is_closed()
+    }
+
+    /// Returns the number of jobs waiting in the injector and all local
+    /// deques.
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Marks the current thread as worker `id` of this scheduler, creating
+    /// the worker's deque if it does not exist yet.
+    pub(crate) fn register(&self, id: usize) {
+        let mut locals = self.locals.write().unwrap();
+        while locals.len() <= id {
+            locals.push(Arc::default());
+        }
+        let local = Arc::as_ptr(&locals[id]);
+        CURRENT.with(|current| current.set(Some((self as *const Scheduler, id, local))));
+    }
+
+    /// Returns the id and deque of the worker running on this thread, if it
+    /// is one of this scheduler's workers.
+    fn current_worker(&self) -> Option<(usize, &LocalQueue)> {
+        CURRENT.with(|current| match current.get() {
+            // SAFETY: deques are never removed from `locals`, so the deque
+            // lives as long as the scheduler.
+            Some((scheduler, id, local)) if std::ptr::eq(scheduler, self) => {
+                Some((id, unsafe { &*local }))
+            }
+            _ => None,
+        })
+    }
+
+    /// Returns whether this thread is one of this scheduler's workers.
+    pub(crate) fn is_worker_thread(&self) -> bool {
+        self.current_worker().is_some()
+    }
+
+    /// Queues `f`. See [`JobQueue::push`] for how a full injector is handled.
+    ///
+    /// Jobs without a priority that are submitted from a worker go to its
+    /// unbounded local deque; everything else goes to the injector, with
+    /// [`Priority::NORMAL`] if no priority is given.
+    pub(crate) fn push<F>(
+        &self,
+        f: F,
+        priority: Option<Priority>,
+        admission: Admission,
+    ) -> Result<(), PoolError<F>>
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        // Counted before the job becomes visible so `pending` never falls
+        // below the number of queued jobs, which would let workers sleep
+        // through it.
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let pushed = match (priority, self.current_worker()) {
+            (None, Some(_)) if self.is_closed() => Err(PoolError::ShutDown(f)),
+            (None, Some((_, local))) => {
+                local.lock().unwrap().push_back(QueuedJob::new(Box::new(f), admission));
+                Ok(None)
+            }
+            (priority, _) => self.injector.push(f, priority.unwrap_or_default(), admission),
+        };
+
+        match pushed {
+            Ok(None) => {
+                self.wake_one();
+                Ok(())
+            }
+            Ok(Some(dropped)) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                drop(dropped);
+                Ok(())
+            }
+            Err(e) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    fn wake_one(&self) {
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let mut notified = self.sleep_lock.lock().unwrap();
+            // Notifying is a system call. Sleepers that are already being
+            // woken look for this job too before they sleep again.
+            if *notified < self.sleepers.load(Ordering::SeqCst) {
+                *notified += 1;
+                self.wake.notify_one();
+            }
+        }
+    }
+
+    fn wake_all(&self) {
+        let mut notified = self.sleep_lock.lock().unwrap();
+        *notified = self.sleepers.load(Ordering::SeqCst);
+        self.wake.notify_all();
+    }
+
+    /// Asks `count` workers to exit. Each exits the next time it asks for a
+    /// job, so busy workers finish their current job first.
+    pub(crate) fn retire(&self, count: usize) {
+        self.retiring.fetch_add(count, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    /// Withdraws up to `count` requests made with
+    /// [`retire`](Scheduler::retire) that no worker has acted on yet. Returns
+    /// how many were withdrawn.
+    pub(crate) fn unretire(&self, count: usize) -> usize {
+        let mut withdrawn = 0;
+        let _ = self
+            .retiring
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retiring| {
+                withdrawn = retiring.min(count);
+                Some(retiring - withdrawn)
+            });
+        withdrawn
+    }
+
+    /// Takes one of the requests made with [`retire`](Scheduler::retire), if
+    /// there is one.
+    fn take_retirement(&self) -> bool {
+        // Checked first so workers do not contend on the counter while no
+        // one is retiring.
+        if self.retiring.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        self.retiring
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retiring| {
+                retiring.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Records that a job returned by [`pop`](Scheduler::pop) has finished.
+    pub(crate) fn finish(&self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        self.notify_if_idle();
+    }
+
+    fn is_idle(&self) -> bool {
+        self.running.load(Ordering::SeqCst) == 0 && self.pending.load(Ordering::SeqCst) == 0
+    }
+
+    fn notify_if_idle(&self) {
+        // A waiter announces itself before checking whether the scheduler
+        // is idle, so either it sees the scheduler idle or we see it.
+        if self.is_idle() && self.idle_waiters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.idle_lock.lock().unwrap();
+            self.idle.notify_all();
+        }
+    }
+
+    /// Blocks until no jobs are queued or running, or until `deadline` if
+    /// one is given. Returns whether the scheduler became idle.
+    pub(crate) fn wait_idle(&self, deadline: Option<Instant>) -> bool {
+        let mut guard = self.idle_lock.lock().unwrap();
+        self.idle_waiters.fetch_add(1, Ordering::SeqCst);
+        let mut idle = true;
+        while !self.is_idle() {
+            match deadline {
+                None => guard = self.idle.wait(guard).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        idle = false;
+                        break;
+                    }
+                    guard = self.idle.wait_timeout(guard, deadline - now).unwrap().0;
+                }
+            }
+        }
+        self.idle_waiters.fetch_sub(1, Ordering::SeqCst);
+        drop(guard);
+        idle
+    }
+
+    /// Returns the number of workers waiting for a job.
+    pub(crate) fn sleepers(&self) -> usize {
+        self.sleepers.load(Ordering::SeqCst)
+    }
+
+    /// Blocks until a job is available for worker `id` and returns it, for
+    /// at most `idle_timeout` if one is given.
+    ///
+    /// Must be called from the thread registered as worker `id`.
+    pub(crate) fn pop(&self, id: usize, idle_timeout: Option<Duration>) -> Pop {
+        let (_, local) = self.current_worker().expect("worker is not registered");
+        let mut spins = 0;
+        loop {
+            if self.take_retirement() {
+                return Pop::Exit;
+            }
+            if let Some(job) = self.find_job(id, local) {
+                return Pop::Job(job);
+            }
+            if spins < SPINS {
+                spins += 1;
+                thread::yield_now();
+                continue;
+            }
+
+            let mut notified = self.sleep_lock.lock().unwrap();
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            let mut timed_out = false;
+            // Rechecked under the lock, after announcing ourselves as a
+            // sleeper, so a concurrent push either sees us or we see it.
+            if self.pending.load(Ordering::SeqCst) == 0
+                && self.retiring.load(Ordering::SeqCst) == 0
+            {
+                if self.is_closed() {
+                    self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                    return Pop::Exit;
+                }
+                match idle_timeout {
+                    None => notified = self.wake.wait(notified).unwrap(),
+                    Some(timeout) => {
+                        let (guard, result) = self.wake.wait_timeout(notified, timeout).unwrap();
+                        notified = guard;
+                        timed_out = result.timed_out();
+                    }
+                }
+                // Whoever the notification was meant for, this worker now
+                // goes looking for the job it announced.
+                *notified = notified.saturating_sub(1);
+            }
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            drop(notified);
+            if timed_out && self.pending.load(Ordering::SeqCst) == 0 {
+                return Pop::TimedOut;
+            }
+        }
+    }
+
+    /// Takes a job for the worker running on this thread to run while it
+    /// waits for other jobs, along with the worker's id. Returns `None` if
+    /// there is no job, or if this thread is not one of this scheduler's
+    /// workers.
+    pub(crate) fn pop_for_current(&self) -> Option<(usize, QueuedJob)> {
+        let (id, local) = self.current_worker()?;
+        self.find_job(id, local).map(|job| (id, job))
+    }
+
+    /// Takes a job for worker `id`, whose deque is `local`.
+    fn find_job(&self, id: usize, local: &LocalQueue) -> Option<QueuedJob> {
+        // Never hold one deque's lock while taking another's: two workers
+        // stealing from each other would deadlock.
+        let own = local.lock().unwrap().pop_back();
+        let job = own.or_else(|| self.injector.try_pop()).or_else(|| self.steal(id));
+        if job.is_some() {
+            // Counted as running first so the scheduler never looks idle
+            // while the job changes hands.
+            self.running.fetch_add(1, Ordering::SeqCst);
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        job
+    }
+
+    /// Takes the oldest job from another worker's deque.
+    fn steal(&self, id: usize) -> Option<QueuedJob> {
+        let locals = self.locals.read().unwrap();
+        (1..locals.len())
+            .map(|offset| &locals[(id + offset) % locals.len()])
+            .find_map(|victim| victim.lock().unwrap().pop_front())
+    }
+
+    /// Stops accepting jobs. Queued jobs are still handed out by
+    /// [`pop`](Scheduler::pop).
+    pub(crate) fn close(&self) {
+        self.injector.close();
+        self.wake_all();
+    }
+
+    /// Closes the scheduler and returns every queued job but those of
+    /// scopes, which are still handed out by [`pop`](Scheduler::pop).
+    pub(crate) fn drain(&self) -> Vec<Job> {
+        let mut jobs = self.injector.drain();
+        for local in self.locals.read().unwrap().iter() {
+            let mut local = local.lock().unwrap();
+            let (kept, discarded): (VecDeque<_>, VecDeque<_>) =
+                local.drain(..).partition(|queued| queued.scoped);
+            *local = kept;
+            jobs.extend(discarded.into_iter().map(|queued| queued.job));
+        }
+        self.pending.fetch_sub(jobs.len(), Ordering::SeqCst);
+        self.notify_if_idle();
+        self.wake_all();
+        jobs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ThreadPool;
+    use std::{
+        collections::HashSet,
+        sync::{mpsc, Arc},
+        time::Duration,
+    };
+
+    #[test]
+    fn idle_workers_steal_nested_jobs() {
+        let pool = Arc::new(ThreadPool::new(4));
+        let (sender, receiver) = mpsc::channel();
+
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            // Queued on this worker's own deque; the other workers steal them.
+            for _ in 0..4 {
+                let sender = sender.clone();
+                inner.execute(move || {
+                    std::thread::sleep(Duration::from_millis(100));
+                    sender.send(std::thread::current().id()).unwrap();
+                });
+            }
+        });
+
+        let threads: HashSet<_> = (0..4)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert!(threads.len() > 1, "nested jobs were not stolen");
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    any::Any,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::{help, stats, Job, ThreadPool};
+
+/// A scope for jobs that borrow from the stack, created by
+/// [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    /// Invariance over `'scope` and `'env`, as in `std::thread::Scope`.
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+#[derive(Default)]
+struct ScopeState {
+    /// Number of jobs that have been submitted and not yet run or dropped.
+    pending: Mutex<usize>,
+    done: Condvar,
+    /// The payload of the first job to panic.
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+/// Decrements the scope's pending jobs when dropped, whether its job ran,
+/// panicked or was discarded by the pool.
+struct PendingJob(Arc<ScopeState>);
+
+/// A job submitted to a scope. Fields drop in declaration order, so `f` and
+/// everything it borrows is gone before the scope learns the job is done.
+struct ScopedJob<F> {
+    f: F,
+    pending: PendingJob,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Queues `f` to run on one of the pool's workers. `f` may borrow
+    /// anything that outlives the scope.
+    ///
+    /// If `f` panics, the scope panics with the same payload once every job
+    /// has finished; the pool's panic handler is not called.
+    ///
+    /// `f` is queued whatever the capacity of the pool's queue, and once
+    /// queued is never discarded, not even by
+    /// [`ThreadPool::shutdown_now`]. If the pool is shutting down, `f` runs
+    /// on the calling thread instead.
+    pub fn execute<F>(&'scope self, f: F)
+        where
+            F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+        let job = ScopedJob {
+            f,
+            pending: PendingJob(Arc::clone(&self.state)),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || job.run());
+        // SAFETY: the job only borrows data that outlives 'scope, and
+        // `ThreadPool::scope` does not return before every `PendingJob` has
+        // been dropped, which happens once the job has run or been dropped
+        // itself.
+        let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.pool.execute_scoped(job);
+    }
+}
+
+impl<F> ScopedJob<F>
+    where
+        F: FnOnce(),
+{
+    fn run(self) {
+        let ScopedJob { f, pending } = self;
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            stats::record_caught_panic();
+            pending.0.panic.lock().unwrap().get_or_insert(payload);
+        }
+    }
+}
+
+impl Drop for PendingJob {
+    fn drop(&mut self) {
+        let mut pending = self.0.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
+
+/// Runs `f` with a new scope on `pool` and waits for every job it submits.
+pub(crate) fn scope<'env, F, T>(pool: &ThreadPool, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        pool,
+        state: Arc::default(),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+    // The jobs may borrow from the caller's stack, so they must finish even
+    // if `f` panics.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+    // A worker waiting for its scope runs queued jobs in the meantime, most
+    // likely the scope's own, so nested scopes cannot tie up every worker.
+    // Once there are none left, the scope's jobs are all running elsewhere.
+    // A panicking panic handler is only re-raised after that, for the same
+    // reason as a panic in `f`.
+    let mut handler_panic = None;
+    while *scope.state.pending.lock().unwrap() > 0 {
+        match help(&pool.shared) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(payload) => {
+                handler_panic.get_or_insert(payload);
+            }
+        }
+    }
+    let mut pending = scope.state.pending.lock().unwrap();
+    while *pending > 0 {
+        pending = scope.state.done.wait(pending).unwrap();
+    }
+    drop(pending);
+
+    if let Some(payload) = handler_panic {
+        panic::resume_unwind(payload);
+    }
+    if let Some(payload) = scope.state.panic.lock().unwrap().take() {
+        panic::resume_unwind(payload);
+    }
+    result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        tests::{gated_job, occupy_worker},
+        RejectionPolicy, ThreadPool, ThreadPoolBuilder,
+    };
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn fib(pool: &ThreadPool, n: u64) -> u64 {
+        if n < 2 {
+            return n;
+        }
+        let (a, b) = pool.join(|| fib(pool, n - 1), || fib(pool, n - 2));
+        a + b
+    }
+
+    #[test]
+    fn jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(4);
+        let mut numbers: Vec<usize> = (0..100).collect();
+        let total = AtomicUsize::new(0);
+
+        pool.scope(|s| {
+            for chunk in numbers.chunks_mut(10) {
+                let total = &total;
+                s.execute(move || {
+                    for n in chunk.iter_mut() {
+                        *n *= 2;
+                        total.fetch_add(*n, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(total.load(Ordering::SeqCst), 9900);
+        assert_eq!(numbers[99], 198);
+    }
+
+    #[test]
+    fn panics_propagate_after_every_job_finishes() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.execute(|| panic!("scoped job failed"));
+                s.execute(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.submit(|| 1).join().unwrap(), 1);
+    }
+
+    #[test]
+    fn nested_joins_run_on_two_workers() {
+        let pool = Arc::new(ThreadPool::new(2));
+        let inner = Arc::clone(&pool);
+        // Every worker waits on a join many times over; without helping they
+        // would all block.
+        let handle = pool.submit(move || fib(&inner, 18));
+        let result = handle.join_timeout(Duration::from_secs(10));
+        assert_eq!(result.expect("nested joins deadlocked").unwrap(), 2584);
+
+        assert_eq!(fib(&pool, 10), 55);
+        pool.wait_idle();
+        let stats = pool.stats();
+        assert_eq!(stats.panicked(), 0);
+        assert!(stats.completed() > 1000);
+    }
+
+    #[test]
+    fn jobs_run_inline_while_the_pool_shuts_down() {
+        let pool = Arc::new(ThreadPool::new(2));
+        let (started, wait_started) = mpsc::channel();
+        let (release, blocked) = gated_job();
+        let inner = Arc::clone(&pool);
+        let handle = pool.submit(move || {
+            started.send(()).unwrap();
+            blocked();
+            inner.join(|| 1, || 2)
+        });
+        wait_started.recv().unwrap();
+
+        let closing = Arc::clone(&pool);
+        let shutdown = thread::spawn(move || closing.shutdown());
+        while pool.try_execute(|| {}).is_ok() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(release);
+
+        assert_eq!(handle.join().unwrap(), (1, 2));
+        shutdown.join().unwrap();
+    }
+
+    #[test]
+    fn panicking_panic_handlers_wait_for_the_scope() {
+        let pool = ThreadPool::new(2);
+        pool.set_panic_handler(|_| panic!("panic handler failed"));
+        let pool = Arc::new(pool);
+
+        let inner = Arc::clone(&pool);
+        let handle = pool.submit(move || {
+            let finished = AtomicUsize::new(0);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                inner.scope(|s| {
+                    s.execute(|| {
+                        thread::sleep(Duration::from_millis(50));
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                    // Queued after the scoped job, so the waiting worker runs
+                    // it first.
+                    inner.execute(|| panic!("job failed"));
+                })
+            }));
+            let payload = result.unwrap_err();
+            (*payload.downcast_ref::<&str>().unwrap(), finished.load(Ordering::SeqCst))
+        });
+
+        assert_eq!(handle.join().unwrap(), ("panic handler failed", 1));
+    }
+
+    #[test]
+    fn queued_jobs_are_never_discarded() {
+        let builder = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::DropOldest);
+        let pool = builder.build().unwrap();
+        let release = occupy_worker(&pool);
+        let ran = AtomicUsize::new(0);
+
+        pool.execute(|| {});
+        let discarded = thread::scope(|threads| {
+            pool.scope(|s| {
+                s.execute(|| {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+                // Each evicts the oldest job, passing over the scoped one.
+                pool.execute(|| {});
+                pool.execute(|| {});
+
+                let shutdown = threads.spawn(|| pool.shutdown_now().len());
+                while pool.try_execute(|| {}).is_ok() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                drop(release);
+                shutdown
+            })
+            .join()
+            .unwrap()
+        });
+
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+        assert_eq!(discarded, 1);
+    }
+
+    #[test]
+    fn join_survives_a_full_drop_oldest_queue() {
+        let builder = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::DropOldest);
+        let pool = builder.build().unwrap();
+        let release = occupy_worker(&pool);
+        pool.execute(|| {});
+
+        let (a, b) = pool.join(
+            || {
+                pool.execute(|| {});
+                pool.execute(|| {});
+                drop(release);
+                1
+            },
+            || 2,
+        );
+        assert_eq!((a, b), (1, 2));
+    }
+}
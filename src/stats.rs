@@ -0,0 +1,336 @@
+//! Runtime statistics.
+//!
+//! Workers keep the counters up to date with a few atomic operations per job;
+//! [`ThreadPool::stats`](crate::ThreadPool::stats) only reads them.
+
+use std::{
+    cell::Cell,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::histogram::{Histogram, HistogramSnapshot};
+
+thread_local! {
+    /// Set by jobs that catch their own panic, so the worker still counts
+    /// them as panicked.
+    static CAUGHT_PANIC: Cell<bool> = const { Cell::new(false) };
+    /// Time the current job has spent running nested jobs, which record
+    /// their own execution time.
+    static NESTED: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// A snapshot of a pool's activity, as returned by
+/// [`ThreadPool::stats`](crate::ThreadPool::stats).
+///
+/// The counters are read one after another while the pool keeps running, so
+/// they need not add up exactly.
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    queued: usize,
+    active: usize,
+    idle: usize,
+    completed: u64,
+    panicked: u64,
+    workers: Vec<WorkerStats>,
+}
+
+/// Histograms of how long jobs wait for a worker and how long they run, as
+/// returned by [`ThreadPool::latency`](crate::ThreadPool::latency).
+#[derive(Clone, Debug)]
+pub struct LatencyStats {
+    queue_wait: HistogramSnapshot,
+    execution: HistogramSnapshot,
+}
+
+impl LatencyStats {
+    /// Returns the time from jobs being queued to a worker starting them.
+    pub fn queue_wait(&self) -> &HistogramSnapshot {
+        &self.queue_wait
+    }
+
+    /// Returns the time workers spent running jobs.
+    pub fn execution(&self) -> &HistogramSnapshot {
+        &self.execution
+    }
+}
+
+/// The activity of a single worker, part of [`PoolStats`].
+#[derive(Clone, Debug)]
+pub struct WorkerStats {
+    id: usize,
+    jobs: u64,
+    busy: Duration,
+}
+
+impl PoolStats {
+    /// Returns the number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    /// Returns the number of workers running a job.
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Returns the number of live workers waiting for a job.
+    pub fn idle(&self) -> usize {
+        self.idle
+    }
+
+    /// Returns the number of jobs that have finished without panicking.
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    /// Returns the number of jobs that have panicked, including jobs passed
+    /// to [`ThreadPool::submit`](crate::ThreadPool::submit) whose panic was
+    /// reported through their handle.
+    pub fn panicked(&self) -> u64 {
+        self.panicked
+    }
+
+    /// Returns the statistics of every worker id the pool has used, ordered
+    /// by id. Ids of workers that exit are given to new workers, and a
+    /// worker carries on from the totals of the workers that had its id
+    /// before it, as does a restarted worker.
+    pub fn workers(&self) -> &[WorkerStats] {
+        &self.workers
+    }
+}
+
+impl WorkerStats {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the number of jobs the worker has finished, whether they
+    /// panicked or not.
+    pub fn jobs(&self) -> u64 {
+        self.jobs
+    }
+
+    /// Returns the total time the worker has spent running jobs. The job it
+    /// is running, if any, is not counted until it finishes.
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+}
+
+pub(crate) struct Stats {
+    /// Per-worker counters, indexed by worker id.
+    workers: RwLock<Vec<Arc<WorkerCounters>>>,
+    /// Number of workers running a job, not counting nested jobs.
+    active: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    queue_wait: Histogram,
+    execution: Histogram,
+}
+
+#[derive(Default)]
+pub(crate) struct WorkerCounters {
+    jobs: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Stats {
+        Stats {
+            workers: RwLock::new(Vec::new()),
+            active: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            queue_wait: Histogram::new(),
+            execution: Histogram::new(),
+        }
+    }
+
+    /// Returns worker `id`'s counters, creating them if they do not exist
+    /// yet.
+    pub(crate) fn register(&self, id: usize) -> Arc<WorkerCounters> {
+        let mut workers = self.workers.write().unwrap();
+        while workers.len() <= id {
+            workers.push(Arc::default());
+        }
+        Arc::clone(&workers[id])
+    }
+
+    /// Returns worker `id`'s counters, which must have been registered.
+    pub(crate) fn counters(&self, id: usize) -> Arc<WorkerCounters> {
+        Arc::clone(&self.workers.read().unwrap()[id])
+    }
+
+    /// Records that a worker is starting a job queued at `queued`. Returns
+    /// the time it started.
+    pub(crate) fn start(&self, queued: Instant) -> Instant {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.begin(queued)
+    }
+
+    fn begin(&self, queued: Instant) -> Instant {
+        CAUGHT_PANIC.with(|caught| caught.set(false));
+        NESTED.with(|nested| nested.set(Duration::ZERO));
+        let started = Instant::now();
+        let waited = started.saturating_duration_since(queued);
+        self.queue_wait.record(waited);
+        started
+    }
+
+    /// Records that the worker owning `counters` has finished the job it
+    /// started at `started`.
+    pub(crate) fn finish(&self, counters: &WorkerCounters, started: Instant, panicked: bool) {
+        self.record(counters, started, panicked, true);
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Runs `job`, queued at `queued`, on the worker owning `counters` while
+    /// the worker waits in the middle of another job. The job's time is not
+    /// added to the worker's busy time, which the waiting job already covers,
+    /// nor to the waiting job's execution time, and the waiting job's caught
+    /// panic, if any, is kept.
+    pub(crate) fn run_nested<F>(
+        &self,
+        counters: &WorkerCounters,
+        queued: Instant,
+        job: F,
+    ) -> thread::Result<()>
+        where
+            F: FnOnce(),
+    {
+        let outer_panic = CAUGHT_PANIC.with(Cell::get);
+        let outer_nested = NESTED.with(Cell::get);
+        let started = self.begin(queued);
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        let elapsed = self.record(counters, started, result.is_err(), false);
+        CAUGHT_PANIC.with(|caught| caught.set(outer_panic));
+        NESTED.with(|nested| nested.set(outer_nested + elapsed));
+        result
+    }
+
+    /// Returns how long the job ran, including any nested jobs.
+    fn record(
+        &self,
+        counters: &WorkerCounters,
+        started: Instant,
+        panicked: bool,
+        busy: bool,
+    ) -> Duration {
+        let elapsed = started.elapsed();
+        self.execution.record(elapsed.saturating_sub(NESTED.with(Cell::get)));
+        if busy {
+            let busy_nanos = elapsed.as_nanos() as u64;
+            counters.busy_nanos.fetch_add(busy_nanos, Ordering::SeqCst);
+        }
+        counters.jobs.fetch_add(1, Ordering::SeqCst);
+        if panicked || CAUGHT_PANIC.with(Cell::get) {
+            self.panicked.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.completed.fetch_add(1, Ordering::SeqCst);
+        }
+        elapsed
+    }
+
+    pub(crate) fn snapshot(&self, queued: usize, alive: usize) -> PoolStats {
+        let active = self.active.load(Ordering::SeqCst);
+        let workers = self
+            .workers
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(id, counters)| WorkerStats {
+                id,
+                jobs: counters.jobs.load(Ordering::SeqCst),
+                busy: Duration::from_nanos(counters.busy_nanos.load(Ordering::SeqCst)),
+            })
+            .collect();
+        PoolStats {
+            queued,
+            active,
+            idle: alive.saturating_sub(active),
+            completed: self.completed.load(Ordering::SeqCst),
+            panicked: self.panicked.load(Ordering::SeqCst),
+            workers,
+        }
+    }
+
+    /// Copies the latency histograms, emptying them if `reset` is set.
+    pub(crate) fn latency(&self, reset: bool) -> LatencyStats {
+        LatencyStats {
+            queue_wait: self.queue_wait.snapshot(reset),
+            execution: self.execution.snapshot(reset),
+        }
+    }
+}
+
+/// Counts the job running on the current worker as panicked even though it
+/// caught the panic itself.
+pub(crate) fn record_caught_panic() {
+    CAUGHT_PANIC.with(|caught| caught.set(true));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::occupy_worker, ThreadPool};
+    use std::{
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn counts_jobs_and_busy_workers() {
+        let pool = ThreadPool::new(2);
+        let release = occupy_worker(&pool);
+
+        let stats = pool.stats();
+        assert_eq!(stats.active(), 1);
+        assert_eq!(stats.idle(), 1);
+        drop(release);
+
+        pool.execute(|| thread::sleep(Duration::from_millis(20)));
+        let _ = pool.submit(|| panic!("counted")).join();
+        pool.shutdown();
+
+        let stats = pool.stats();
+        assert_eq!(stats.queued(), 0);
+        assert_eq!(stats.active(), 0);
+        assert_eq!(stats.completed(), 2);
+        assert_eq!(stats.panicked(), 1);
+        assert_eq!(stats.workers().iter().map(|w| w.jobs()).sum::<u64>(), 3);
+        let busy: Duration = stats.workers().iter().map(|w| w.busy()).sum();
+        assert!(busy >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn nested_jobs_are_not_counted_twice() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let inner = Arc::clone(&pool);
+        // The only worker runs the scoped job itself while it waits.
+        let handle = pool.submit(move || {
+            let mut active = 0;
+            inner.scope(|s| {
+                s.execute(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    active = inner.stats().active();
+                });
+            });
+            active
+        });
+        assert_eq!(handle.join().unwrap(), 1);
+
+        pool.wait_idle();
+        assert_eq!(pool.stats().idle(), 1);
+        let latency = pool.latency();
+        let execution = latency.execution();
+        assert_eq!(execution.count(), 2);
+        assert!(execution.sum() < Duration::from_millis(100));
+    }
+}
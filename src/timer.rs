@@ -0,0 +1,270 @@
+//! Delayed jobs.
+//!
+//! A single timer thread per pool keeps delayed jobs in a heap ordered by
+//! their due time and hands each one to the scheduler once it is due.
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    sync::{
+        atomic::{self, AtomicU8},
+        Arc, Condvar, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{logging::debug, queue::Admission, scheduler::Scheduler, Job};
+
+const PENDING: u8 = 0;
+const FIRED: u8 = 1;
+const CANCELLED: u8 = 2;
+/// Being handed to the scheduler, which may still turn the job down.
+const FIRING: u8 = 3;
+
+/// A handle to a job scheduled with
+/// [`ThreadPool::execute_after`](crate::ThreadPool::execute_after) or
+/// [`ThreadPool::execute_at`](crate::ThreadPool::execute_at).
+#[derive(Clone, Debug)]
+pub struct TimerHandle {
+    status: Arc<AtomicU8>,
+}
+
+impl TimerHandle {
+    /// Cancels the job if it has not been handed to a worker yet. Returns
+    /// whether the job was cancelled by this call.
+    pub fn cancel(&self) -> bool {
+        self.status
+            .compare_exchange(
+                PENDING,
+                CANCELLED,
+                atomic::Ordering::SeqCst,
+                atomic::Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+
+    /// Returns whether the job was cancelled, either through a handle or by
+    /// the pool shutting down before the job could be queued.
+    pub fn is_cancelled(&self) -> bool {
+        self.status.load(atomic::Ordering::SeqCst) == CANCELLED
+    }
+
+    /// Returns whether the job has been queued for a worker.
+    pub fn has_fired(&self) -> bool {
+        self.status.load(atomic::Ordering::SeqCst) == FIRED
+    }
+}
+
+pub(crate) struct Timer {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+struct TimerState {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+    stopped: bool,
+}
+
+/// A delayed job. The heap hands out the earliest `due` first, then the
+/// lowest `seq`.
+struct Entry {
+    due: Instant,
+    seq: u64,
+    job: Job,
+    status: Arc<AtomicU8>,
+}
+
+impl Timer {
+    pub(crate) fn new() -> Timer {
+        Timer {
+            state: Mutex::new(TimerState {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Schedules `job` to be handed to the scheduler at `due`. Returns `None`
+    /// if the timer has been stopped.
+    pub(crate) fn schedule(&self, due: Instant, job: Job) -> Option<TimerHandle> {
+        let status = Arc::new(AtomicU8::new(PENDING));
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return None;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry {
+            due,
+            seq,
+            job,
+            status: Arc::clone(&status),
+        });
+        drop(state);
+        self.changed.notify_one();
+        Some(TimerHandle { status })
+    }
+
+    /// Runs the timer thread until [`stop`](Timer::stop) is called.
+    pub(crate) fn run(&self, scheduler: &Scheduler) {
+        let mut state = self.state.lock().unwrap();
+        while !state.stopped {
+            let now = Instant::now();
+            match state.entries.peek().map(|entry| entry.due) {
+                Some(due) if due <= now => {
+                    let entry = state.entries.pop().unwrap();
+                    drop(state);
+                    entry.fire(scheduler);
+                    state = self.state.lock().unwrap();
+                }
+                Some(due) => state = self.changed.wait_timeout(state, due - now).unwrap().0,
+                None => state = self.changed.wait(state).unwrap(),
+            }
+        }
+    }
+
+    /// Stops the timer thread and returns the delayed jobs that had not been
+    /// cancelled. Their handles report them as cancelled.
+    pub(crate) fn stop(&self) -> Vec<Job> {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        let entries = std::mem::take(&mut state.entries);
+        drop(state);
+        self.changed.notify_all();
+        entries
+            .into_sorted_vec()
+            .into_iter()
+            .rev()
+            .filter(|entry| entry.claim(CANCELLED))
+            .map(|entry| entry.job)
+            .collect()
+    }
+}
+
+impl Entry {
+    /// Moves a pending entry to `status`. Returns false if it was cancelled.
+    fn claim(&self, status: u8) -> bool {
+        self.status
+            .compare_exchange(
+                PENDING,
+                status,
+                atomic::Ordering::SeqCst,
+                atomic::Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+
+    fn fire(self, scheduler: &Scheduler) {
+        if !self.claim(FIRING) {
+            return;
+        }
+        // A full queue is not a reason to drop the job, nor to hold up every
+        // other delayed job behind it, so only a shutdown turns it down.
+        let status = match scheduler.push(self.job, None, Admission::Bypass) {
+            Ok(()) => FIRED,
+            Err(e) => {
+                debug!("Dropped delayed job: {}", e);
+                CANCELLED
+            }
+        };
+        self.status.store(status, atomic::Ordering::SeqCst);
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        // `BinaryHeap` is a max-heap, so the comparison is reversed.
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Entry {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::occupy_worker, RejectionPolicy, ThreadPool, ThreadPoolBuilder};
+    use std::{
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn delayed_jobs_run_in_due_order() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        let start = Instant::now();
+
+        let later = sender.clone();
+        pool.execute_after(Duration::from_millis(100), move || {
+            later.send("later").unwrap()
+        });
+        pool.execute_at(start + Duration::from_millis(30), move || {
+            sender.send("sooner").unwrap()
+        });
+
+        assert_eq!(receiver.recv().unwrap(), "sooner");
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(receiver.recv().unwrap(), "later");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn cancelled_jobs_do_not_run() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+
+        let cancelled = sender.clone();
+        let handle = pool.execute_after(Duration::from_millis(50), move || {
+            cancelled.send("cancelled").unwrap()
+        });
+        let kept = pool.execute_after(Duration::from_millis(100), move || {
+            sender.send("kept").unwrap()
+        });
+        assert!(handle.cancel());
+        assert!(handle.is_cancelled());
+
+        assert_eq!(receiver.recv().unwrap(), "kept");
+        assert!(kept.has_fired());
+        assert!(!kept.cancel());
+    }
+
+    #[test]
+    fn due_jobs_are_queued_even_when_the_queue_is_full() {
+        for policy in [RejectionPolicy::FailFast, RejectionPolicy::CallerRuns] {
+            let builder = ThreadPoolBuilder::new()
+                .num_threads(1)
+                .queue_capacity(1)
+                .rejection_policy(policy);
+            let pool = builder.build().unwrap();
+            let release = occupy_worker(&pool);
+            pool.execute(|| {});
+
+            let (sender, receiver) = mpsc::channel();
+            let handle = pool.execute_after(Duration::from_millis(10), move || {
+                sender.send(thread::current().id()).unwrap()
+            });
+            while !handle.has_fired() {
+                assert!(!handle.is_cancelled());
+                thread::sleep(Duration::from_millis(1));
+            }
+            drop(release);
+            assert_ne!(receiver.recv().unwrap(), thread::current().id());
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Detection of stuck jobs.
+//!
+//! While enabled, every worker records when its current job started in a
+//! slot of its own. A watchdog thread scans the slots and reports each job
+//! that outlives its timeout once.
+
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::logging::warning;
+
+/// The longest the watchdog sleeps between scans, which bounds how late a
+/// stuck job can be reported.
+const CHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+thread_local! {
+    /// The watchdog slot of the pool worker running on this thread.
+    static SLOT: RefCell<Option<Arc<Slot>>> = const { RefCell::new(None) };
+}
+
+pub(crate) type StuckJobHandler = Box<dyn Fn(&StuckJob) + Send + Sync + 'static>;
+
+/// A job that has been running for longer than its timeout, as passed to
+/// [`ThreadPoolBuilder::stuck_job_handler`](crate::ThreadPoolBuilder::stuck_job_handler).
+#[derive(Clone, Debug)]
+pub struct StuckJob {
+    worker: usize,
+    running_for: Duration,
+    timeout: Duration,
+    started: Instant,
+}
+
+impl StuckJob {
+    /// Returns the id of the worker running the job.
+    pub fn worker(&self) -> usize {
+        self.worker
+    }
+
+    /// Returns how long the job had been running when it was reported.
+    pub fn running_for(&self) -> Duration {
+        self.running_for
+    }
+
+    /// Returns the timeout the job exceeded.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+pub(crate) struct Watchdog {
+    /// Per-worker slots, indexed by worker id.
+    slots: RwLock<Vec<Arc<Slot>>>,
+    /// The timeout of jobs submitted without one.
+    timeout: Option<Duration>,
+    handler: Option<StuckJobHandler>,
+    replace: bool,
+    /// Set once the watchdog thread has been started. Workers only track
+    /// their jobs from then on.
+    enabled: AtomicBool,
+    stuck: AtomicUsize,
+    stopped: Mutex<bool>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+pub(crate) struct Slot {
+    running: Mutex<Option<Running>>,
+}
+
+struct Running {
+    started: Instant,
+    timeout: Option<Duration>,
+    reported: bool,
+    /// Set when a replacement worker has been spawned for this one, which
+    /// should exit once the job finishes.
+    retire: bool,
+}
+
+impl Watchdog {
+    pub(crate) fn new(
+        timeout: Option<Duration>,
+        handler: Option<StuckJobHandler>,
+        replace: bool,
+    ) -> Watchdog {
+        Watchdog {
+            slots: RwLock::new(Vec::new()),
+            timeout,
+            handler,
+            replace,
+            enabled: AtomicBool::new(false),
+            stuck: AtomicUsize::new(0),
+            stopped: Mutex::new(false),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Returns whether every job has a timeout, so the watchdog should run
+    /// from the start.
+    pub(crate) fn has_default_timeout(&self) -> bool {
+        self.timeout.is_some()
+    }
+
+    pub(crate) fn replaces_stuck_workers(&self) -> bool {
+        self.replace
+    }
+
+    /// Returns the number of jobs reported as stuck so far.
+    pub(crate) fn stuck_count(&self) -> usize {
+        self.stuck.load(Ordering::SeqCst)
+    }
+
+    /// Returns worker `id`'s slot, creating it if it does not exist yet, and
+    /// makes it the current thread's slot.
+    pub(crate) fn register(&self, id: usize) -> Arc<Slot> {
+        let mut slots = self.slots.write().unwrap();
+        while slots.len() <= id {
+            slots.push(Arc::default());
+        }
+        let slot = Arc::clone(&slots[id]);
+        *slot.running.lock().unwrap() = None;
+        SLOT.with(|current| *current.borrow_mut() = Some(Arc::clone(&slot)));
+        slot
+    }
+
+    /// Records that the worker owning `slot` is starting a job.
+    pub(crate) fn start(&self, slot: &Slot) {
+        if self.enabled.load(Ordering::SeqCst) {
+            *slot.running.lock().unwrap() = Some(Running {
+                started: Instant::now(),
+                timeout: self.timeout,
+                reported: false,
+                retire: false,
+            });
+        }
+    }
+
+    /// Records that the worker owning `slot` has finished its job. Returns
+    /// whether the worker has been replaced and should exit.
+    pub(crate) fn finish(&self, slot: &Slot) -> bool {
+        // Once enabled, the watchdog stays enabled, so a disabled watchdog
+        // has not tracked the job.
+        if !self.enabled.load(Ordering::SeqCst) {
+            return false;
+        }
+        slot.running
+            .lock()
+            .unwrap()
+            .take()
+            .is_some_and(|running| running.retire)
+    }
+
+    /// Marks the watchdog as running, so workers start tracking their jobs.
+    pub(crate) fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Blocks until at least one job has outlived its timeout and returns
+    /// the newly stuck jobs, or returns `None` once the watchdog is stopped.
+    pub(crate) fn wait(&self) -> Option<Vec<StuckJob>> {
+        let mut stopped = self.stopped.lock().unwrap();
+        while !*stopped {
+            let now = Instant::now();
+            let mut next_check = now + CHECK_INTERVAL;
+            let mut stuck = Vec::new();
+            for (worker, slot) in self.slots.read().unwrap().iter().enumerate() {
+                let mut running = slot.running.lock().unwrap();
+                let Some(running) = running.as_mut().filter(|running| !running.reported) else {
+                    continue;
+                };
+                let Some(timeout) = running.timeout else {
+                    continue;
+                };
+                let deadline = running.started + timeout;
+                if deadline <= now {
+                    running.reported = true;
+                    stuck.push(StuckJob {
+                        worker,
+                        running_for: now - running.started,
+                        timeout,
+                        started: running.started,
+                    });
+                } else {
+                    next_check = next_check.min(deadline);
+                }
+            }
+            if !stuck.is_empty() {
+                return Some(stuck);
+            }
+            stopped = self
+                .changed
+                .wait_timeout(stopped, next_check - now)
+                .unwrap()
+                .0;
+        }
+        None
+    }
+
+    /// Counts `job` as stuck and tells the handler about it.
+    pub(crate) fn report(&self, job: &StuckJob) {
+        self.stuck.fetch_add(1, Ordering::SeqCst);
+        warning!(
+            "Job on worker {} has been running for {:?}, over its {:?} timeout",
+            job.worker,
+            job.running_for,
+            job.timeout
+        );
+        if let Some(handler) = &self.handler {
+            handler(job);
+        }
+    }
+
+    /// Asks the worker running `job` to exit once `job` finishes. Returns
+    /// false if `job` has already finished.
+    pub(crate) fn retire(&self, job: &StuckJob) -> bool {
+        self.set_retire(job, true)
+    }
+
+    /// Undoes [`retire`](Watchdog::retire). Returns false if `job` has
+    /// already finished, in which case its worker has exited.
+    pub(crate) fn unretire(&self, job: &StuckJob) -> bool {
+        self.set_retire(job, false)
+    }
+
+    fn set_retire(&self, job: &StuckJob, retire: bool) -> bool {
+        let slot = Arc::clone(&self.slots.read().unwrap()[job.worker]);
+        let mut running = slot.running.lock().unwrap();
+        match running.as_mut() {
+            Some(running) if running.started == job.started => {
+                running.retire = retire;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Gives the job running on the current worker its own timeout. Does
+/// nothing outside a worker or while the watchdog is not running.
+pub(crate) fn set_timeout(timeout: Duration) {
+    SLOT.with(|slot| {
+        if let Some(slot) = slot.borrow().as_ref() {
+            if let Some(running) = slot.running.lock().unwrap().as_mut() {
+                running.timeout = Some(timeout);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::gated_job, ThreadPoolBuilder};
+    use std::{sync::mpsc, thread, time::Duration};
+
+    #[test]
+    fn reports_jobs_over_their_timeout() {
+        let (sender, receiver) = mpsc::channel();
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .job_timeout(Duration::from_millis(50))
+            .stuck_job_handler(move |job| sender.send(job.clone()).unwrap())
+            .build()
+            .unwrap();
+
+        pool.execute(|| thread::sleep(Duration::from_millis(200)));
+        pool.execute(|| {});
+
+        let job = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(job.worker(), 0);
+        assert_eq!(job.timeout(), Duration::from_millis(50));
+        assert!(job.running_for() >= Duration::from_millis(50));
+        pool.shutdown();
+        assert_eq!(pool.stuck_job_count(), 1);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn stuck_workers_are_replaced() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .replace_stuck_workers(true)
+            .build()
+            .unwrap();
+        let (release, blocked) = gated_job();
+        pool.execute_with_timeout(Duration::from_millis(20), blocked);
+
+        // Only a replacement worker can run this while the first is stuck.
+        let handle = pool.submit(|| thread::current().id());
+        assert!(handle.join_timeout(Duration::from_secs(5)).is_some());
+        assert_eq!(pool.stuck_job_count(), 1);
+        drop(release);
+    }
+}